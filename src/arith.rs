@@ -0,0 +1,386 @@
+use crate::Shell;
+
+// Shell arithmetic: integer expressions as used by array subscripts and the
+// substring operator. Variables are referred to by bare name and evaluated
+// recursively; an unset or empty variable counts as 0.
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArithToken {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest operators first so that the tokenizer is greedy.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=",
+    "/=", "%=", "&=", "|=", "^=", "+", "-", "*", "/", "%", "<", ">", "!", "~", "&", "|", "^", "?",
+    ":", "=", "(", ")", ",",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<ArithToken>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_digit() {
+            let len = chars[i..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '#').count();
+            let text: String = chars[i..i + len].iter().collect();
+            tokens.push(ArithToken::Number(parse_number(&text)?));
+            i += len;
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            let len = chars[i..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').count();
+            tokens.push(ArithToken::Name(chars[i..i + len].iter().collect()));
+            i += len;
+            continue;
+        }
+        for op in OPERATORS {
+            let op_len = op.len();
+            if i + op_len <= chars.len() && chars[i..i + op_len].iter().copied().eq(op.chars()) {
+                tokens.push(ArithToken::Op(op));
+                i += op_len;
+                continue 'outer;
+            }
+        }
+        return Err(format!("{}: syntax error: invalid arithmetic operator (error token is \"{}\")", expr, chars[i..].iter().collect::<String>()));
+    }
+    Ok(tokens)
+}
+
+// Parses decimal, octal (leading 0), hex (0x) and base#value numbers.
+fn parse_number(text: &str) -> Result<i64, String> {
+    let invalid = || format!("{}: value too great for base (error token is \"{}\")", text, text);
+    if let Some((base, digits)) = text.split_once('#') {
+        let base: u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=36).contains(&base) {
+            return Err(format!("{}: invalid arithmetic base", text));
+        }
+        return i64::from_str_radix(digits, base).map_err(|_| invalid());
+    }
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).map_err(|_| invalid());
+    }
+    if text.len() > 1 && text.starts_with('0') {
+        return i64::from_str_radix(&text[1..], 8).map_err(|_| invalid());
+    }
+    text.parse().map_err(|_| invalid())
+}
+
+struct Parser<'a> {
+    shell: &'a mut Shell,
+    tokens: Vec<ArithToken>,
+    pos: usize,
+    expr: String,
+    // When false, the current branch is not taken (short-circuit or the
+    // unused side of `?:`), so assignments are parsed but not performed.
+    evaluate: bool,
+    // How many variables deep the evaluation is, to stop `a=b b=a` looping.
+    depth: usize,
+}
+
+const MAX_DEPTH: usize = 1024;
+
+// Evaluates an arithmetic expression. An empty expression evaluates to 0.
+pub fn eval(shell: &mut Shell, expr: &str) -> Result<i64, String> {
+    eval_nested(shell, expr, 0)
+}
+
+fn eval_nested(shell: &mut Shell, expr: &str, depth: usize) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("{}: expression recursion level exceeded", expr.trim()));
+    }
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { shell, tokens, pos: 0, expr: expr.to_string(), evaluate: true, depth };
+    let value = parser.comma()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+impl Parser<'_> {
+    fn error(&self) -> String {
+        format!("{}: syntax error in expression", self.expr.trim())
+    }
+
+    fn peek(&self) -> Option<&ArithToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(ArithToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> Result<i64, String> {
+        let value = self.shell.vars.get(name).unwrap_or("").trim().to_string();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(number) = parse_number(&value) {
+            return Ok(number);
+        }
+        eval_nested(self.shell, &value, self.depth + 1)
+    }
+
+    fn assign(&mut self, name: &str, value: i64) {
+        if self.evaluate {
+            self.shell.vars.set(name, &value.to_string());
+        }
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, String> {
+        if let (Some(ArithToken::Name(name)), Some(ArithToken::Op(op))) =
+            (self.tokens.get(self.pos).cloned(), self.tokens.get(self.pos + 1).cloned())
+        {
+            if op.ends_with('=') && !matches!(op, "==" | "!=" | "<=" | ">=") {
+                self.pos += 2;
+                let rhs = self.assignment()?;
+                let value = if op == "=" {
+                    rhs
+                } else {
+                    let current = self.variable(&name)?;
+                    self.binary(&op[..op.len() - 1], current, rhs)?
+                };
+                self.assign(&name, value);
+                return Ok(value);
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = self.binary_level(0)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let outer = self.evaluate;
+        self.evaluate = outer && condition != 0;
+        let then_value = self.assignment()?;
+        self.expect(":")?;
+        self.evaluate = outer && condition == 0;
+        let else_value = self.conditional()?;
+        self.evaluate = outer;
+        Ok(if condition != 0 { then_value } else { else_value })
+    }
+
+    // Binary operators from loosest to tightest binding.
+    const LEVELS: &'static [&'static [&'static str]] = &[
+        &["||"],
+        &["&&"],
+        &["|"],
+        &["^"],
+        &["&"],
+        &["==", "!="],
+        &["<", "<=", ">", ">="],
+        &["<<", ">>"],
+        &["+", "-"],
+        &["*", "/", "%"],
+    ];
+
+    fn binary_level(&mut self, level: usize) -> Result<i64, String> {
+        if level == Self::LEVELS.len() {
+            return self.power();
+        }
+        let mut left = self.binary_level(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| Self::LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let outer = self.evaluate;
+            if (op == "&&" && left == 0) || (op == "||" && left != 0) {
+                self.evaluate = false;
+            }
+            let right = self.binary_level(level + 1)?;
+            self.evaluate = outer;
+            left = self.binary(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if self.peek_op() == Some("**") {
+            self.pos += 1;
+            let exponent = self.power()?;
+            return self.binary("**", base, exponent);
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.peek_op() {
+            Some(op @ ("-" | "+" | "!" | "~")) => {
+                self.pos += 1;
+                let value = self.unary()?;
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "+" => value,
+                    "!" => (value == 0) as i64,
+                    _ => !value,
+                })
+            }
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let Some(ArithToken::Name(name)) = self.peek().cloned() else {
+                    return Err(self.error());
+                };
+                self.pos += 1;
+                let delta = if op == "++" { 1 } else { -1 };
+                let value = self.variable(&name)?.wrapping_add(delta);
+                self.assign(&name, value);
+                Ok(value)
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<i64, String> {
+        match self.peek().cloned() {
+            Some(ArithToken::Number(n)) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            Some(ArithToken::Name(name)) => {
+                self.pos += 1;
+                let value = self.variable(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_op() {
+                    self.pos += 1;
+                    let delta = if op == "++" { 1 } else { -1 };
+                    self.assign(&name, value.wrapping_add(delta));
+                }
+                Ok(value)
+            }
+            Some(ArithToken::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn binary(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        let divide_check = || {
+            if right == 0 && self.evaluate {
+                Err(format!("{}: division by 0", self.expr.trim()))
+            } else {
+                Ok(())
+            }
+        };
+        Ok(match op {
+            "||" => (left != 0 || right != 0) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" => {
+                divide_check()?;
+                if right == 0 { 0 } else { left.wrapping_div(right) }
+            }
+            "%" => {
+                divide_check()?;
+                if right == 0 { 0 } else { left.wrapping_rem(right) }
+            }
+            "**" => {
+                if right < 0 {
+                    return Err(format!("{}: exponent less than 0", self.expr.trim()));
+                }
+                wrapping_pow(left, right as u64)
+            }
+            _ => return Err(self.error()),
+        })
+    }
+}
+
+// `base ** exp` wrapping on overflow, by repeated squaring over the whole
+// exponent rather than the 32 bits `i64::wrapping_pow` takes.
+fn wrapping_pow(mut base: i64, mut exp: u64) -> i64 {
+    let mut result: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping_pow_uses_the_whole_exponent() {
+        assert_eq!(wrapping_pow(2, 4294967297), 0);
+        assert_eq!(wrapping_pow(3, 4294967297), 7473929035676909571);
+        assert_eq!(wrapping_pow(-1, 4294967297), -1);
+        assert_eq!(wrapping_pow(2, 62), 1 << 62);
+        assert_eq!(wrapping_pow(2, 63), i64::MIN);
+        assert_eq!(wrapping_pow(2, 64), 0);
+        assert_eq!(wrapping_pow(5, 0), 1);
+        assert_eq!(wrapping_pow(0, 0), 1);
+    }
+
+    #[test]
+    fn negative_exponent_is_an_error() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(eval(&mut shell, "2**4294967297"), Ok(0));
+        assert!(eval(&mut shell, "2**-1").is_err());
+    }
+
+    #[test]
+    fn arithmetic_wraps_on_overflow() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(eval(&mut shell, "9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval(&mut shell, "-9223372036854775807 - 2"), Ok(i64::MAX));
+        assert_eq!(eval(&mut shell, "4611686018427387904 * 4"), Ok(0));
+        assert_eq!(eval(&mut shell, "(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(eval(&mut shell, "(-9223372036854775807 - 1) % -1"), Ok(0));
+        assert_eq!(eval(&mut shell, "x = 9223372036854775807, ++x"), Ok(i64::MIN));
+        assert_eq!(eval(&mut shell, "y = -9223372036854775807 - 1, y--, y"), Ok(i64::MAX));
+    }
+}
@@ -0,0 +1,1883 @@
+use std::env;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::conditional;
+use crate::expand;
+use crate::parser;
+use crate::printf;
+use crate::variables::{is_valid_name, parse_assignment, parse_name_with_subscript, Value, Variable};
+use crate::{find_all_in_path, find_executable_in_path, HashEntry, Shell, BUILTINS, SHOPT_OPTIONS};
+
+// Builtins that are large enough to live outside `run_single_command`.
+// Each one appends to the command's standard output and standard error
+// buffers and returns its exit status.
+
+// Quotes a value for `declare -p` output so that it can be read back.
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn describe_variable(name: &str, var: &Variable) -> String {
+    let mut flags = String::new();
+    match &var.value {
+        Value::Indexed(_) => flags.push('a'),
+        Value::Assoc(_) => flags.push('A'),
+        Value::Scalar(_) => {}
+    }
+    if var.exported {
+        flags.push('x');
+    }
+    if flags.is_empty() {
+        flags.push('-');
+    }
+
+    let value = match &var.value {
+        Value::Scalar(value) => double_quote(value),
+        Value::Indexed(map) => {
+            let items: Vec<String> = map.iter().map(|(i, v)| format!("[{}]={}", i, double_quote(v))).collect();
+            format!("({})", items.join(" "))
+        }
+        Value::Assoc(map) => {
+            let items: Vec<String> = map.iter().map(|(k, v)| format!("[{}]={}", k, double_quote(v))).collect();
+            format!("({})", items.join(" "))
+        }
+    };
+    format!("declare -{} {}={}\n", flags, name, value)
+}
+
+// declare [-aAxp] [name[=value] ...]
+pub fn declare(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let mut indexed = false;
+    let mut assoc = false;
+    let mut export = false;
+    let mut print = false;
+
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+        if args[i] == "--" {
+            i += 1;
+            break;
+        }
+        for flag in args[i][1..].chars() {
+            match flag {
+                'a' => indexed = true,
+                'A' => assoc = true,
+                'x' => export = true,
+                'p' => print = true,
+                // There are no function scopes yet, so every variable is global.
+                'g' => {}
+                _ => {
+                    err.push_str(&format!("declare: -{}: invalid option\ndeclare: usage: declare [-aAgpx] [name[=value] ...]\n", flag));
+                    return 2;
+                }
+            }
+        }
+        i += 1;
+    }
+    let names = &args[i..];
+
+    if names.is_empty() || print {
+        let mut status = 0;
+        let all: Vec<String>;
+        let selected: Vec<&str> = if names.is_empty() {
+            all = shell.vars.names().into_iter().map(|n| n.to_string()).collect();
+            all.iter().map(|n| n.as_str()).collect()
+        } else {
+            names.to_vec()
+        };
+        for name in selected {
+            match shell.vars.get_var(name) {
+                Some(var) => out.push_str(&describe_variable(name, var)),
+                None => {
+                    err.push_str(&format!("declare: {}: not found\n", name));
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let name = match parse_assignment(arg) {
+            Some(assignment) => assignment.name,
+            None => *arg,
+        };
+        if !is_valid_name(name) {
+            err.push_str(&format!("declare: `{}': not a valid identifier\n", arg));
+            status = 1;
+            continue;
+        }
+
+        let declared = if assoc {
+            shell.vars.declare_assoc(name)
+        } else if indexed {
+            shell.vars.declare_indexed(name)
+        } else {
+            Ok(())
+        };
+        let assigned = declared.and_then(|_| {
+            if parse_assignment(arg).is_some() {
+                expand::assign(shell, arg)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = assigned {
+            err.push_str(&format!("declare: {}\n", e));
+            status = 1;
+            continue;
+        }
+        if export {
+            shell.vars.export(name);
+        }
+    }
+    status
+}
+
+// unset [-f|-v] name[subscript] ...
+pub fn unset(shell: &mut Shell, args: &[&str], err: &mut String) -> i32 {
+    let mut status = 0;
+    let names = match args.first() {
+        Some(&"-v") | Some(&"--") => &args[1..],
+        Some(&"-f") => {
+            for name in &args[1..] {
+                shell.functions.remove(*name);
+            }
+            return 0;
+        }
+        _ => args,
+    };
+    for name in names {
+        if let Err(e) = expand::unset(shell, name) {
+            err.push_str(&format!("unset: {}\n", e));
+            status = 1;
+        }
+    }
+    status
+}
+
+// shift [n]
+pub fn shift(shell: &mut Shell, args: &[&str], err: &mut String) -> i32 {
+    let count = match args.first() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                err.push_str(&format!("shift: {}: numeric argument required\n", arg));
+                return 1;
+            }
+        },
+        None => 1,
+    };
+    if count > shell.positional.len() {
+        return 1;
+    }
+    shell.positional.drain(..count);
+    0
+}
+
+// set [--] [arg ...]
+// Without arguments, lists the shell variables. Otherwise the arguments
+// replace the positional parameters.
+pub fn set(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    if args.is_empty() {
+        for name in shell.vars.names() {
+            if let Some(Variable { value: Value::Scalar(value), .. }) = shell.vars.get_var(name) {
+                out.push_str(&format!("{}={}\n", name, double_quote(value)));
+            }
+        }
+        return 0;
+    }
+
+    let args = match args[0] {
+        "--" => &args[1..],
+        arg if arg.starts_with(['-', '+']) && arg.len() > 1 => {
+            err.push_str(&format!("set: {}: invalid option\nset: usage: set [--] [arg ...]\n", arg));
+            return 2;
+        }
+        _ => args,
+    };
+    shell.positional = args.iter().map(|arg| arg.to_string()).collect();
+    0
+}
+
+// getopts optstring name [arg ...]
+// Parses one option from the arguments (the positional parameters by
+// default) per call, keeping its place in OPTIND.
+pub fn getopts(shell: &mut Shell, args: &[&str], err: &mut String) -> i32 {
+    if args.len() < 2 {
+        err.push_str("getopts: usage: getopts optstring name [arg ...]\n");
+        return 2;
+    }
+    let (optstring, name) = (args[0], args[1]);
+    if !is_valid_name(name) {
+        err.push_str(&format!("getopts: `{}': not a valid identifier\n", name));
+        return 1;
+    }
+    let silent = optstring.starts_with(':');
+    let optstring = optstring.trim_start_matches(':');
+    let params: Vec<String> = if args.len() > 2 {
+        args[2..].iter().map(|arg| arg.to_string()).collect()
+    } else {
+        shell.positional.clone()
+    };
+
+    let mut optind = shell.vars.get("OPTIND").and_then(|v| v.parse::<usize>().ok()).unwrap_or(1).max(1);
+    // OPTIND was changed behind our back, e.g. reset to 1 to parse again,
+    // or the arguments are not the ones being scanned before.
+    if optind != shell.getopts_optind || params.get(optind - 1) != Some(&shell.getopts_arg) {
+        shell.getopts_offset = 0;
+    }
+
+    let finish = |shell: &mut Shell, optind: usize, option: &str, optarg: Option<&str>| {
+        shell.getopts_optind = optind;
+        shell.vars.set("OPTIND", &optind.to_string());
+        shell.vars.set(name, option);
+        match optarg {
+            Some(optarg) => shell.vars.set("OPTARG", optarg),
+            None => shell.vars.unset("OPTARG"),
+        }
+    };
+
+    let arg = match params.get(optind - 1) {
+        Some(arg) if shell.getopts_offset > 0 || (arg.starts_with('-') && arg != "-") => arg.as_str(),
+        _ => {
+            finish(shell, optind, "?", None);
+            return 1;
+        }
+    };
+    if arg == "--" && shell.getopts_offset == 0 {
+        finish(shell, optind + 1, "?", None);
+        return 1;
+    }
+
+    let chars: Vec<char> = arg.chars().collect();
+    let offset = shell.getopts_offset.max(1);
+    let Some(&option) = chars.get(offset) else {
+        shell.getopts_offset = 0;
+        finish(shell, optind, "?", None);
+        return 1;
+    };
+    let rest: String = chars[offset + 1..].iter().collect();
+    if rest.is_empty() {
+        optind += 1;
+        shell.getopts_offset = 0;
+    } else {
+        shell.getopts_offset = offset + 1;
+        shell.getopts_arg = arg.to_string();
+    }
+
+    let spec = optstring.find(option).filter(|_| option != ':');
+    let Some(spec) = spec else {
+        if silent {
+            finish(shell, optind, "?", Some(&option.to_string()));
+        } else {
+            err.push_str(&format!("{}: illegal option -- {}\n", shell.script_name, option));
+            finish(shell, optind, "?", None);
+        }
+        return 0;
+    };
+
+    if !optstring[spec + option.len_utf8()..].starts_with(':') {
+        finish(shell, optind, &option.to_string(), None);
+        return 0;
+    }
+
+    // The option takes an argument: the rest of this word or the next one.
+    if !rest.is_empty() {
+        shell.getopts_offset = 0;
+        finish(shell, optind + 1, &option.to_string(), Some(&rest));
+    } else if let Some(value) = params.get(optind - 1) {
+        finish(shell, optind + 1, &option.to_string(), Some(value));
+    } else if silent {
+        finish(shell, optind, ":", Some(&option.to_string()));
+    } else {
+        err.push_str(&format!("{}: option requires an argument -- {}\n", shell.script_name, option));
+        finish(shell, optind, "?", None);
+    }
+    0
+}
+
+// test expr, [ expr ]
+pub fn test(shell: &mut Shell, name: &str, args: &[&str], err: &mut String) -> i32 {
+    let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    if name == "[" {
+        if args.last().map(|arg| arg.as_str()) != Some("]") {
+            err.push_str("[: missing `]'\n");
+            return 2;
+        }
+        args.pop();
+    }
+    match conditional::test(shell, &args) {
+        Ok(result) => !result as i32,
+        Err(e) => {
+            err.push_str(&format!("{}: {}\n", name, e));
+            2
+        }
+    }
+}
+
+// An option letter and its value, if it takes one.
+type ParsedOption<'a> = (char, Option<&'a str>);
+
+// Splits the leading options off `args` the way getopts does. `spec` lists
+// the option letters, with a `:` after those that take a value. On an
+// error the message is added to `err` and None is returned.
+fn parse_options<'a, 'b>(
+    name: &str,
+    spec: &str,
+    args: &'b [&'a str],
+    err: &mut String,
+) -> Option<(Vec<ParsedOption<'a>>, &'b [&'a str])> {
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with('-') && args[i].len() > 1 {
+        let arg = args[i];
+        i += 1;
+        if arg == "--" {
+            break;
+        }
+        for (offset, flag) in arg.char_indices().skip(1) {
+            let Some(position) = spec.find(flag).filter(|_| flag != ':') else {
+                err.push_str(&format!("{}: -{}: invalid option\n", name, flag));
+                return None;
+            };
+            if !spec[position + 1..].starts_with(':') {
+                options.push((flag, None));
+                continue;
+            }
+            // The value is the rest of this argument or the next one.
+            let rest = &arg[offset + flag.len_utf8()..];
+            if !rest.is_empty() {
+                options.push((flag, Some(rest)));
+            } else if let Some(value) = args.get(i) {
+                options.push((flag, Some(*value)));
+                i += 1;
+            } else {
+                err.push_str(&format!("{}: -{}: option requires an argument\n", name, flag));
+                return None;
+            }
+            break;
+        }
+    }
+    Some((options, &args[i..]))
+}
+
+enum InputByte {
+    Byte(u8),
+    Eof,
+    Timeout,
+}
+
+// Reads one byte from standard input. Input is read straight from fd 0 a
+// byte at a time so that nothing past the delimiter is taken away from the
+// commands that run next.
+fn read_byte(deadline: Option<Instant>) -> InputByte {
+    if let Some(deadline) = deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut poll_fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis() as libc::c_int) } <= 0 {
+            return InputByte::Timeout;
+        }
+    }
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(0, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => return InputByte::Byte(byte),
+            -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => continue,
+            _ => return InputByte::Eof,
+        }
+    }
+}
+
+// Switches off echo and/or line editing on the terminal until dropped.
+struct TerminalMode {
+    saved: libc::termios,
+}
+
+impl TerminalMode {
+    fn set(echo: bool, canonical: bool) -> Option<Self> {
+        unsafe {
+            if libc::isatty(0) != 1 {
+                return None;
+            }
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(0, &mut saved) != 0 {
+                return None;
+            }
+            let mut mode = saved;
+            if !echo {
+                mode.c_lflag &= !libc::ECHO;
+            }
+            if !canonical {
+                mode.c_lflag &= !libc::ICANON;
+                mode.c_cc[libc::VMIN] = 1;
+                mode.c_cc[libc::VTIME] = 0;
+            }
+            libc::tcsetattr(0, libc::TCSANOW, &mode);
+            Some(TerminalMode { saved })
+        }
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+// Splits the characters read by `read` into at most `max_fields` fields on
+// IFS. Escaped characters never split, and the last field takes the rest
+// of the line.
+fn split_input(chars: &[(char, bool)], ifs: &str, max_fields: usize) -> Vec<String> {
+    let is_delimiter = |i: usize| !chars[i].1 && ifs.contains(chars[i].0);
+    let is_blank = |i: usize| is_delimiter(i) && chars[i].0.is_whitespace();
+    let mut fields = Vec::new();
+    let mut i = 0;
+    while i < chars.len() && is_blank(i) {
+        i += 1;
+    }
+
+    while i < chars.len() {
+        if fields.len() + 1 == max_fields {
+            let mut end = chars.len();
+            while end > i && is_blank(end - 1) {
+                end -= 1;
+            }
+            fields.push(chars[i..end].iter().map(|(c, _)| c).collect());
+            break;
+        }
+        let start = i;
+        while i < chars.len() && !is_delimiter(i) {
+            i += 1;
+        }
+        fields.push(chars[start..i].iter().map(|(c, _)| c).collect());
+
+        while i < chars.len() && is_blank(i) {
+            i += 1;
+        }
+        if i < chars.len() && is_delimiter(i) {
+            i += 1;
+            while i < chars.len() && is_blank(i) {
+                i += 1;
+            }
+        }
+    }
+    fields
+}
+
+// Turns bytes into characters, each keeping the escaped flag of its first byte.
+fn decode_input(bytes: &[(u8, bool)]) -> Vec<(char, bool)> {
+    let mut chars = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let len = match bytes[i].0 {
+            b if b >= 0xf0 => 4,
+            b if b >= 0xe0 => 3,
+            b if b >= 0xc0 => 2,
+            _ => 1,
+        };
+        let end = (i + len).min(bytes.len());
+        let raw: Vec<u8> = bytes[i..end].iter().map(|(b, _)| *b).collect();
+        match std::str::from_utf8(&raw) {
+            Ok(text) => chars.extend(text.chars().map(|c| (c, bytes[i].1))),
+            Err(_) => chars.push((char::REPLACEMENT_CHARACTER, bytes[i].1)),
+        }
+        i = end;
+    }
+    chars
+}
+
+// read [-rs] [-a array] [-d delim] [-n nchars] [-p prompt] [-t timeout] [name ...]
+pub fn read(shell: &mut Shell, args: &[&str], err: &mut String) -> i32 {
+    let usage = "read: usage: read [-rs] [-a array] [-d delim] [-n nchars] [-p prompt] [-t timeout] [name ...]\n";
+    let Some((options, names)) = parse_options("read", "a:d:n:p:rst:", args, err) else {
+        err.push_str(usage);
+        return 2;
+    };
+
+    let mut raw = false;
+    let mut silent = false;
+    let mut array = None;
+    let mut delimiter = b'\n';
+    let mut limit = None;
+    let mut prompt = None;
+    let mut timeout = None;
+    for (flag, value) in options {
+        let value = value.unwrap_or("");
+        match flag {
+            'r' => raw = true,
+            's' => silent = true,
+            'a' => array = Some(value),
+            'd' => delimiter = value.bytes().next().unwrap_or(0),
+            'p' => prompt = Some(value),
+            'n' => match value.parse::<usize>() {
+                Ok(count) => limit = Some(count),
+                Err(_) => {
+                    err.push_str(&format!("read: {}: invalid number\n", value));
+                    return 1;
+                }
+            },
+            _ => match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => timeout = Some(Duration::from_secs_f64(seconds)),
+                _ => {
+                    err.push_str(&format!("read: {}: invalid timeout specification\n", value));
+                    return 1;
+                }
+            },
+        }
+    }
+    for name in names.iter().chain(array.iter()) {
+        if !is_valid_name(name) {
+            err.push_str(&format!("read: `{}': not a valid identifier\n", name));
+            return 1;
+        }
+    }
+
+    // `-t 0` only reports whether input is waiting.
+    if timeout == Some(Duration::ZERO) {
+        let mut poll_fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+        return (unsafe { libc::poll(&mut poll_fd, 1, 0) } <= 0) as i32;
+    }
+
+    let _ = std::io::stdout().flush();
+    let is_terminal = unsafe { libc::isatty(0) } == 1;
+    if let (Some(prompt), true) = (prompt, is_terminal) {
+        eprint!("{}", prompt);
+        let _ = std::io::stderr().flush();
+    }
+    let _mode = if silent || limit.is_some() { TerminalMode::set(!silent, limit.is_none()) } else { None };
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut bytes: Vec<(u8, bool)> = Vec::new();
+    let mut count = 0;
+    // Continuation bytes still expected for the current character.
+    let mut pending = 0;
+    let mut status = 0;
+    loop {
+        if limit == Some(count) && pending == 0 {
+            break;
+        }
+        let mut byte = match read_byte(deadline) {
+            InputByte::Byte(byte) => byte,
+            InputByte::Eof => {
+                status = 1;
+                break;
+            }
+            InputByte::Timeout => {
+                status = 128 + libc::SIGALRM;
+                break;
+            }
+        };
+        let mut escaped = false;
+        if pending == 0 && byte == delimiter {
+            break;
+        }
+        if pending == 0 && byte == b'\\' && !raw {
+            match read_byte(deadline) {
+                // A backslash-newline continues the line.
+                InputByte::Byte(b'\n') => continue,
+                InputByte::Byte(next) => {
+                    byte = next;
+                    escaped = true;
+                }
+                _ => {
+                    status = 1;
+                    break;
+                }
+            }
+        }
+        bytes.push((byte, escaped));
+        if pending > 0 {
+            pending -= 1;
+        } else {
+            pending = match byte {
+                b if b >= 0xf0 => 3,
+                b if b >= 0xe0 => 2,
+                b if b >= 0xc0 => 1,
+                _ => 0,
+            };
+        }
+        if pending == 0 {
+            count += 1;
+        }
+    }
+
+    let chars = decode_input(&bytes);
+    let ifs = shell.vars.get("IFS").unwrap_or(" \t\n").to_string();
+    if let Some(array) = array {
+        let fields = split_input(&chars, &ifs, usize::MAX);
+        shell.vars.set_value(array, Value::Indexed(fields.into_iter().enumerate().collect()));
+    } else if names.is_empty() {
+        let line: String = chars.iter().map(|(c, _)| c).collect();
+        shell.vars.set("REPLY", &line);
+    } else {
+        let mut fields = split_input(&chars, &ifs, names.len()).into_iter();
+        for name in names {
+            shell.vars.set(name, &fields.next().unwrap_or_default());
+        }
+    }
+    status
+}
+
+// mapfile [-t] [-d delim] [-n count] [-O origin] [-s count] [array]
+pub fn mapfile(shell: &mut Shell, name: &str, args: &[&str], err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options(name, "d:n:O:s:t", args, err) else {
+        err.push_str(&format!("{}: usage: {} [-t] [-d delim] [-n count] [-O origin] [-s count] [array]\n", name, name));
+        return 2;
+    };
+
+    let mut trim = false;
+    let mut delimiter = b'\n';
+    let mut max_lines = 0;
+    let mut origin = None;
+    let mut skip = 0;
+    for (flag, value) in options {
+        let value = value.unwrap_or("");
+        let number = || value.parse::<usize>().map_err(|_| format!("{}: {}: invalid number\n", name, value));
+        let parsed = match flag {
+            't' => {
+                trim = true;
+                Ok(())
+            }
+            'd' => {
+                delimiter = value.bytes().next().unwrap_or(0);
+                Ok(())
+            }
+            'n' => number().map(|n| max_lines = n),
+            'O' => number().map(|n| origin = Some(n)),
+            _ => number().map(|n| skip = n),
+        };
+        if let Err(e) = parsed {
+            err.push_str(&e);
+            return 1;
+        }
+    }
+
+    let array = names.first().copied().unwrap_or("MAPFILE");
+    if !is_valid_name(array) {
+        err.push_str(&format!("{}: `{}': not a valid identifier\n", name, array));
+        return 1;
+    }
+    if shell.vars.is_assoc(array) {
+        err.push_str(&format!("{}: {}: not an indexed array\n", name, array));
+        return 1;
+    }
+    // Without -O the array is emptied first.
+    let mut index = match origin {
+        Some(origin) => {
+            if let Err(e) = shell.vars.declare_indexed(array) {
+                err.push_str(&format!("{}: {}\n", name, e));
+                return 1;
+            }
+            origin
+        }
+        None => {
+            shell.vars.set_value(array, Value::Indexed(Default::default()));
+            0
+        }
+    };
+
+    let _ = std::io::stdout().flush();
+    let mut lines_read = 0;
+    loop {
+        if max_lines > 0 && lines_read == max_lines {
+            break;
+        }
+        let mut line = Vec::new();
+        let mut at_eof = false;
+        loop {
+            match read_byte(None) {
+                InputByte::Byte(byte) => {
+                    if byte == delimiter {
+                        if !trim {
+                            line.push(byte);
+                        }
+                        break;
+                    }
+                    line.push(byte);
+                }
+                _ => {
+                    at_eof = true;
+                    break;
+                }
+            }
+        }
+        if at_eof && line.is_empty() {
+            break;
+        }
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            shell.vars.set_index(array, index, &String::from_utf8_lossy(&line));
+            index += 1;
+            lines_read += 1;
+        }
+        if at_eof {
+            break;
+        }
+    }
+    0
+}
+
+// printf [-v var] format [arguments]
+pub fn printf(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let usage = "printf: usage: printf [-v var] format [arguments]\n";
+    let Some((options, args)) = parse_options("printf", "v:", args, err) else {
+        err.push_str(usage);
+        return 2;
+    };
+    let target = options.first().and_then(|(_, value)| *value);
+    let Some((format, args)) = args.split_first() else {
+        err.push_str(usage);
+        return 2;
+    };
+
+    let formatted = printf::format(format, args);
+    for e in &formatted.errors {
+        err.push_str(&format!("printf: {}\n", e));
+    }
+    let status = (!formatted.errors.is_empty()) as i32;
+
+    let Some(target) = target else {
+        out.push_str(&formatted.text);
+        return status;
+    };
+    let assigned = match parse_name_with_subscript(target) {
+        Some((name, Some(subscript))) => expand::assign_element(shell, name, subscript, &formatted.text, false),
+        Some((name, None)) => {
+            shell.vars.set(name, &formatted.text);
+            Ok(())
+        }
+        None => Err(format!("`{}': not a valid identifier", target)),
+    };
+    match assigned {
+        Ok(()) => status,
+        Err(e) => {
+            err.push_str(&format!("printf: {}\n", e));
+            1
+        }
+    }
+}
+
+// echo [-neE] [arg ...]
+// An argument is only taken as options if it is made of those letters.
+// With the xpg_echo option set, escapes are interpreted by default.
+pub fn echo(shell: &Shell, args: &[&str], out: &mut String) -> i32 {
+    let mut newline = true;
+    let mut escapes = shell.shopt_enabled("xpg_echo");
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        let Some(flags) = arg.strip_prefix('-').filter(|f| !f.is_empty() && f.chars().all(|c| "neE".contains(c))) else {
+            break;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        i += 1;
+    }
+
+    let text = args[i..].join(" ");
+    if !escapes {
+        out.push_str(&text);
+    } else {
+        let escaped = printf::expand_escapes(&text, false);
+        out.push_str(&escaped.text);
+        // `\c` suppresses everything after it, the newline included.
+        if escaped.stop {
+            return 0;
+        }
+    }
+    if newline {
+        out.push('\n');
+    }
+    0
+}
+
+// shopt [-pqsu] [optname ...]
+pub fn shopt(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options("shopt", "pqsu", args, err) else {
+        err.push_str("shopt: usage: shopt [-pqsu] [optname ...]\n");
+        return 2;
+    };
+    let flag = |c: char| options.iter().any(|(f, _)| *f == c);
+    let (set, unset, quiet, reusable) = (flag('s'), flag('u'), flag('q'), flag('p'));
+    if set && unset {
+        err.push_str("shopt: cannot set and unset shell options simultaneously\n");
+        return 1;
+    }
+
+    let mut status = 0;
+    for name in names {
+        if !SHOPT_OPTIONS.contains(name) {
+            err.push_str(&format!("shopt: {}: invalid shell option name\n", name));
+            status = 1;
+        }
+    }
+    if status != 0 {
+        return status;
+    }
+
+    if set || unset {
+        for name in names {
+            if set {
+                shell.shopts.insert(name.to_string());
+            } else {
+                shell.shopts.remove(*name);
+            }
+        }
+        // With no names, -s and -u list the options that are on or off.
+        if !names.is_empty() {
+            return 0;
+        }
+    }
+
+    let listed: Vec<&str> = if names.is_empty() { SHOPT_OPTIONS.to_vec() } else { names.to_vec() };
+    for name in listed {
+        let enabled = shell.shopt_enabled(name);
+        if (set && !enabled) || (unset && enabled) {
+            continue;
+        }
+        if !enabled {
+            status = 1;
+        }
+        if quiet {
+            continue;
+        }
+        if reusable {
+            out.push_str(&format!("shopt {} {}\n", if enabled { "-s" } else { "-u" }, name));
+        } else {
+            out.push_str(&format!("{:<15}\t{}\n", name, if enabled { "on" } else { "off" }));
+        }
+    }
+    if names.is_empty() { 0 } else { status }
+}
+
+// The text of an I/O error without the "(os error N)" that Rust appends.
+fn error_message(e: &std::io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+// Removes `.` and `..` from an absolute path without resolving symbolic
+// links, so that `..` goes back the way `cd` came.
+fn normalize_logical(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(part) => normalized.push(part),
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// The current directory. The logical one is $PWD, which may go through
+// symbolic links, as long as it still names the current directory.
+pub fn current_dir(shell: &Shell, physical: bool) -> Result<PathBuf, String> {
+    if !physical {
+        if let Some(pwd) = shell.vars.get("PWD").map(PathBuf::from) {
+            if pwd.is_absolute() && normalize_logical(&pwd) == pwd && same_file(&pwd, Path::new(".")) {
+                return Ok(pwd);
+            }
+        }
+    }
+    env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .map_err(|e| format!("error retrieving current directory: {}", error_message(&e)))
+}
+
+// Changes the current directory and updates PWD and OLDPWD. In logical
+// mode a relative `target` is taken from $PWD and `..` removes the last
+// component; if that path cannot be used the physical one is tried.
+pub fn change_directory(shell: &mut Shell, target: &str, physical: bool) -> Result<(), String> {
+    let old = current_dir(shell, false).ok();
+    let fail = |e: std::io::Error| format!("{}: {}", target, error_message(&e));
+
+    let logical = match (&old, physical) {
+        (_, true) => None,
+        (_, false) if target.starts_with('/') => Some(normalize_logical(Path::new(target))),
+        (Some(old), false) => Some(normalize_logical(&old.join(target))),
+        (None, false) => None,
+    };
+    let new = match logical {
+        Some(logical) if env::set_current_dir(&logical).is_ok() => logical,
+        _ => {
+            env::set_current_dir(target).map_err(fail)?;
+            current_dir(shell, true)?
+        }
+    };
+
+    if let Some(old) = old {
+        shell.vars.set("OLDPWD", &old.to_string_lossy());
+        shell.vars.export("OLDPWD");
+    }
+    shell.vars.set("PWD", &new.to_string_lossy());
+    shell.vars.export("PWD");
+    Ok(())
+}
+
+// cd [-L|-P] [dir]
+pub fn cd(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, operands)) = parse_options("cd", "LP", args, err) else {
+        err.push_str("cd: usage: cd [-L|-P] [dir]\n");
+        return 2;
+    };
+    let physical = options.last().is_some_and(|(flag, _)| *flag == 'P');
+    if operands.len() > 1 {
+        err.push_str("cd: too many arguments\n");
+        return 1;
+    }
+
+    let mut print = false;
+    let mut target = match operands.first() {
+        None => match shell.vars.get("HOME") {
+            Some(home) => home.to_string(),
+            None => {
+                err.push_str("cd: HOME not set\n");
+                return 1;
+            }
+        },
+        Some(&"-") => match shell.vars.get("OLDPWD") {
+            Some(oldpwd) => {
+                print = true;
+                oldpwd.to_string()
+            }
+            None => {
+                err.push_str("cd: OLDPWD not set\n");
+                return 1;
+            }
+        },
+        Some(dir) => dir.to_string(),
+    };
+
+    // A relative name that does not start with `.` or `..` is looked up
+    // in each directory of CDPATH first.
+    let first = Path::new(&target).components().next();
+    if !target.is_empty() && matches!(first, Some(Component::Normal(_))) {
+        if let Some(cdpath) = shell.vars.get("CDPATH").map(|v| v.to_string()) {
+            for dir in cdpath.split(':') {
+                let candidate = if dir.is_empty() { target.clone() } else { format!("{}/{}", dir, target) };
+                if Path::new(&candidate).is_dir() {
+                    print = !dir.is_empty() && dir != ".";
+                    target = candidate;
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Err(e) = change_directory(shell, &target, physical) {
+        err.push_str(&format!("cd: {}\n", e));
+        return 1;
+    }
+    if print {
+        out.push_str(&format!("{}\n", shell.vars.get("PWD").unwrap_or_default()));
+    }
+    0
+}
+
+// pwd [-LP]
+pub fn pwd(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, _)) = parse_options("pwd", "LP", args, err) else {
+        err.push_str("pwd: usage: pwd [-LP]\n");
+        return 2;
+    };
+    let physical = options.last().is_some_and(|(flag, _)| *flag == 'P');
+    match current_dir(shell, physical) {
+        Ok(dir) => {
+            out.push_str(&format!("{}\n", dir.display()));
+            0
+        }
+        Err(e) => {
+            err.push_str(&format!("pwd: {}\n", e));
+            1
+        }
+    }
+}
+
+// The directory stack as `dirs` shows it: the current directory first.
+fn directory_stack(shell: &Shell) -> Vec<String> {
+    let current = match current_dir(shell, false) {
+        Ok(dir) => dir.to_string_lossy().into_owned(),
+        Err(_) => shell.vars.get("PWD").unwrap_or_default().to_string(),
+    };
+    std::iter::once(current).chain(shell.dir_stack.iter().cloned()).collect()
+}
+
+// Resolves `+N`, `-N` or `N` to a position in a stack of `len` entries.
+// `+N` counts from the top and `-N` from the bottom, both from zero.
+fn stack_index(spec: &str, len: usize) -> Option<usize> {
+    let (from_bottom, digits) = match spec.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, spec.strip_prefix('+').unwrap_or(spec)),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let n: usize = digits.parse().ok()?;
+    if n >= len {
+        return None;
+    }
+    Some(if from_bottom { len - 1 - n } else { n })
+}
+
+fn is_stack_index(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with(['+', '-']) && arg[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+// The directory for `~N`, `~+N` or `~-N`.
+pub fn stack_entry(shell: &Shell, spec: &str) -> Option<String> {
+    let stack = directory_stack(shell);
+    stack_index(spec, stack.len()).map(|i| stack[i].clone())
+}
+
+// Replaces a leading $HOME with `~`.
+fn abbreviate_home(shell: &Shell, dir: &str) -> String {
+    match shell.vars.get("HOME").filter(|home| !home.is_empty() && *home != "/") {
+        Some(home) => match dir.strip_prefix(home) {
+            Some("") => "~".to_string(),
+            Some(rest) if rest.starts_with('/') => format!("~{}", rest),
+            _ => dir.to_string(),
+        },
+        None => dir.to_string(),
+    }
+}
+
+fn print_stack(shell: &Shell, out: &mut String) {
+    let entries: Vec<String> = directory_stack(shell).iter().map(|dir| abbreviate_home(shell, dir)).collect();
+    out.push_str(&format!("{}\n", entries.join(" ")));
+}
+
+// Makes `entries[0]` the current directory and the rest the stack below it.
+fn set_stack(shell: &mut Shell, mut entries: Vec<String>, change: bool) -> Result<(), String> {
+    let top = entries.remove(0);
+    if change {
+        change_directory(shell, &top, false)?;
+    }
+    shell.dir_stack = entries;
+    Ok(())
+}
+
+// pushd [-n] [+N | -N | dir]
+pub fn pushd(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let mut change = true;
+    let mut operands = Vec::new();
+    for arg in args {
+        match *arg {
+            "-n" => change = false,
+            "--" => {}
+            arg => operands.push(arg),
+        }
+    }
+    if operands.len() > 1 {
+        err.push_str("pushd: too many arguments\n");
+        return 1;
+    }
+
+    let mut stack = directory_stack(shell);
+    let result = match operands.first() {
+        // With no argument the top two entries swap places.
+        None => {
+            if stack.len() < 2 {
+                err.push_str("pushd: no other directory\n");
+                return 1;
+            }
+            stack.swap(0, 1);
+            set_stack(shell, stack, change)
+        }
+        // +N and -N rotate the stack so that entry N is on top.
+        Some(spec) if is_stack_index(spec) => {
+            if stack.len() < 2 {
+                err.push_str("pushd: directory stack empty\n");
+                return 1;
+            }
+            let Some(index) = stack_index(spec, stack.len()) else {
+                err.push_str(&format!("pushd: {}: directory stack index out of range\n", spec));
+                return 1;
+            };
+            stack.rotate_left(index);
+            set_stack(shell, stack, change)
+        }
+        Some(dir) if !change => {
+            stack.insert(1, dir.to_string());
+            set_stack(shell, stack, false)
+        }
+        Some(dir) => change_directory(shell, dir, false).map(|_| shell.dir_stack = stack),
+    };
+    if let Err(e) = result {
+        err.push_str(&format!("pushd: {}\n", e));
+        return 1;
+    }
+    print_stack(shell, out);
+    0
+}
+
+// popd [-n] [+N | -N]
+pub fn popd(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let mut change = true;
+    let mut spec = None;
+    for arg in args {
+        match *arg {
+            "-n" => change = false,
+            "--" => {}
+            arg if is_stack_index(arg) && spec.is_none() => spec = Some(arg),
+            arg => {
+                err.push_str(&format!("popd: {}: invalid argument\npopd: usage: popd [-n] [+N | -N]\n", arg));
+                return 2;
+            }
+        }
+    }
+
+    let mut stack = directory_stack(shell);
+    if stack.len() < 2 {
+        err.push_str("popd: directory stack empty\n");
+        return 1;
+    }
+    let index = match spec {
+        Some(spec) => match stack_index(spec, stack.len()) {
+            Some(index) => index,
+            None => {
+                err.push_str(&format!("popd: {}: directory stack index out of range\n", spec));
+                return 1;
+            }
+        },
+        // -n without N removes the entry below the top.
+        None if !change => 1,
+        None => 0,
+    };
+    stack.remove(index);
+    if let Err(e) = set_stack(shell, stack, change && index == 0) {
+        err.push_str(&format!("popd: {}\n", e));
+        return 1;
+    }
+    print_stack(shell, out);
+    0
+}
+
+// dirs [-clpv] [+N | -N]
+pub fn dirs(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let (mut clear, mut long, mut per_line, mut verbose) = (false, false, false, false);
+    let mut spec = None;
+    for arg in args {
+        if is_stack_index(arg) {
+            spec = Some(*arg);
+            continue;
+        }
+        let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            err.push_str(&format!("dirs: {}: invalid argument\ndirs: usage: dirs [-clpv] [+N] [-N]\n", arg));
+            return 2;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'c' => clear = true,
+                'l' => long = true,
+                'p' => per_line = true,
+                'v' => verbose = true,
+                _ => {
+                    err.push_str(&format!("dirs: -{}: invalid option\ndirs: usage: dirs [-clpv] [+N] [-N]\n", flag));
+                    return 2;
+                }
+            }
+        }
+    }
+    if clear {
+        shell.dir_stack.clear();
+        return 0;
+    }
+
+    let stack = directory_stack(shell);
+    let show = |dir: &str| if long { dir.to_string() } else { abbreviate_home(shell, dir) };
+    if let Some(spec) = spec {
+        match stack_index(spec, stack.len()) {
+            Some(index) => out.push_str(&format!("{}\n", show(&stack[index]))),
+            None => {
+                err.push_str(&format!("dirs: {}: directory stack index out of range\n", spec));
+                return 1;
+            }
+        }
+        return 0;
+    }
+    if verbose {
+        for (i, dir) in stack.iter().enumerate() {
+            out.push_str(&format!("{:2}  {}\n", i, show(dir)));
+        }
+    } else if per_line {
+        for dir in &stack {
+            out.push_str(&format!("{}\n", show(dir)));
+        }
+    } else {
+        let entries: Vec<String> = stack.iter().map(|dir| show(dir)).collect();
+        out.push_str(&format!("{}\n", entries.join(" ")));
+    }
+    0
+}
+
+// What a command name refers to, in the order the shell looks names up.
+pub enum CommandKind {
+    Keyword,
+    Function,
+    Builtin,
+    File(PathBuf),
+    // A program found through the hash table.
+    Hashed(PathBuf),
+}
+
+impl CommandKind {
+    fn name(&self) -> &'static str {
+        match self {
+            CommandKind::Keyword => "keyword",
+            CommandKind::Function => "function",
+            CommandKind::Builtin => "builtin",
+            CommandKind::File(_) | CommandKind::Hashed(_) => "file",
+        }
+    }
+
+    fn describe(&self, name: &str) -> String {
+        match self {
+            CommandKind::Keyword => format!("{} is a shell keyword\n", name),
+            CommandKind::Function => format!("{} is a function\n", name),
+            CommandKind::Builtin => format!("{} is a shell builtin\n", name),
+            CommandKind::File(path) => format!("{} is {}\n", name, path.display()),
+            CommandKind::Hashed(path) => format!("{} is hashed ({})\n", name, path.display()),
+        }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        match self {
+            CommandKind::File(path) | CommandKind::Hashed(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+// Everything `name` could refer to, with the one that would run first.
+pub fn resolve_command(shell: &Shell, name: &str) -> Vec<CommandKind> {
+    let mut kinds = Vec::new();
+    if parser::is_reserved_word(name) {
+        kinds.push(CommandKind::Keyword);
+    }
+    if shell.functions.contains_key(name) {
+        kinds.push(CommandKind::Function);
+    }
+    if BUILTINS.contains(&name) {
+        kinds.push(CommandKind::Builtin);
+    }
+    let hashed = shell.hashed(name).cloned();
+    let found: Vec<PathBuf> = find_all_in_path(name, shell.vars.get("PATH").unwrap_or_default()).into_iter().filter(|path| Some(path) != hashed.as_ref()).collect();
+    kinds.extend(hashed.map(CommandKind::Hashed));
+    kinds.extend(found.into_iter().map(CommandKind::File));
+    kinds
+}
+
+// type [-afptP] name [name ...]
+pub fn type_builtin(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options("type", "afptP", args, err) else {
+        err.push_str("type: usage: type [-afptP] name [name ...]\n");
+        return 2;
+    };
+    let flag = |c: char| options.iter().any(|(f, _)| *f == c);
+    let (all, no_functions, kind_only, path_search) = (flag('a'), flag('f'), flag('t'), flag('P'));
+    let path_only = flag('p') || path_search;
+
+    let mut status = 0;
+    for name in names {
+        let mut kinds = resolve_command(shell, name);
+        if no_functions {
+            kinds.retain(|kind| !matches!(kind, CommandKind::Function));
+        }
+        // -P searches PATH even if the name is also something else.
+        if path_search {
+            kinds.retain(|kind| kind.path().is_some());
+        }
+        if !all {
+            kinds.truncate(1);
+        }
+        if kinds.is_empty() {
+            if !kind_only && !path_only {
+                err.push_str(&format!("type: {}: not found\n", name));
+            }
+            status = 1;
+            continue;
+        }
+        for kind in kinds {
+            if kind_only {
+                out.push_str(&format!("{}\n", kind.name()));
+            } else if path_only {
+                if let Some(path) = kind.path() {
+                    out.push_str(&format!("{}\n", path.display()));
+                }
+            } else {
+                out.push_str(&kind.describe(name));
+            }
+        }
+    }
+    status
+}
+
+// command -v|-V name [name ...]
+// Running `command name args` is handled where commands are run.
+pub fn command(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options("command", "pvV", args, err) else {
+        err.push_str("command: usage: command [-pVv] command [arg ...]\n");
+        return 2;
+    };
+    let verbose = options.iter().any(|(flag, _)| *flag == 'V');
+
+    let mut status = 0;
+    for name in names {
+        match resolve_command(shell, name).into_iter().next() {
+            Some(kind) if verbose => out.push_str(&kind.describe(name)),
+            Some(kind) => match kind.path() {
+                Some(path) => out.push_str(&format!("{}\n", path.display())),
+                None => out.push_str(&format!("{}\n", name)),
+            },
+            None => {
+                if verbose {
+                    err.push_str(&format!("command: {}: not found\n", name));
+                }
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+// which [-a] name [name ...]
+// Prints where in PATH each program is, ignoring builtins and functions.
+pub fn which(shell: &Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options("which", "a", args, err) else {
+        err.push_str("which: usage: which [-a] name [name ...]\n");
+        return 2;
+    };
+    let all = !options.is_empty();
+
+    let mut status = 0;
+    for name in names {
+        let mut paths = find_all_in_path(name, shell.vars.get("PATH").unwrap_or_default());
+        if !all {
+            paths.truncate(1);
+        }
+        if paths.is_empty() {
+            status = 1;
+        }
+        for path in paths {
+            out.push_str(&format!("{}\n", path.display()));
+        }
+    }
+    status
+}
+
+// hash [-lrt] [-p pathname] [-d] [name ...]
+pub fn hash(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, names)) = parse_options("hash", "dlp:rt", args, err) else {
+        err.push_str("hash: usage: hash [-lr] [-p pathname] [-dt] [name ...]\n");
+        return 2;
+    };
+    let flag = |c: char| options.iter().any(|(f, _)| *f == c);
+    let pathname = options.iter().find_map(|(f, value)| (*f == 'p').then_some(*value).flatten());
+    shell.check_hash_path();
+
+    let mut status = 0;
+    let mut changed = false;
+    if flag('r') {
+        shell.hash_table.clear();
+        changed = true;
+    }
+    for name in names {
+        if let Some(path) = pathname {
+            shell.hash_table.insert(name.to_string(), HashEntry { path: PathBuf::from(path), hits: 0 });
+            changed = true;
+        } else if flag('d') {
+            if shell.hash_table.remove(*name).is_none() {
+                err.push_str(&format!("hash: {}: not found\n", name));
+                status = 1;
+            }
+            changed = true;
+        } else if flag('t') {
+            match shell.hash_table.get(*name) {
+                Some(entry) if names.len() > 1 => out.push_str(&format!("{}\t{}\n", name, entry.path.display())),
+                Some(entry) => out.push_str(&format!("{}\n", entry.path.display())),
+                None => {
+                    err.push_str(&format!("hash: {}: not found\n", name));
+                    status = 1;
+                }
+            }
+        } else if !BUILTINS.contains(name) && !shell.functions.contains_key(*name) {
+            match find_executable_in_path(name, &shell.hash_path) {
+                Some(path) if !name.contains('/') => {
+                    shell.hash_table.insert(name.to_string(), HashEntry { path, hits: 0 });
+                }
+                Some(_) => {}
+                None => {
+                    err.push_str(&format!("hash: {}: not found\n", name));
+                    status = 1;
+                }
+            }
+        }
+    }
+    if changed {
+        shell.hash_changed();
+    }
+
+    if names.is_empty() && !flag('r') && pathname.is_none() {
+        if shell.hash_table.is_empty() {
+            out.push_str("hash: hash table empty\n");
+        } else if flag('l') {
+            for (name, entry) in &shell.hash_table {
+                out.push_str(&format!("builtin hash -p {} {}\n", entry.path.display(), name));
+            }
+        } else {
+            out.push_str("hits\tcommand\n");
+            for entry in shell.hash_table.values() {
+                out.push_str(&format!("{:4}\t{}\n", entry.hits, entry.path.display()));
+            }
+        }
+    }
+    status
+}
+
+const HISTORY_USAGE: &str =
+    "history: usage: history [-c] [-d offset] [n] or history -anrw [filename] or history -ps arg [arg...]\n";
+
+// Turns a `history -d` position into an index into the history list,
+// whose first entry is number `base + 1`. Negative positions count back
+// from the end, -1 being the last entry.
+fn history_index(position: &str, len: usize, base: usize) -> Option<usize> {
+    let number: i64 = position.parse().ok()?;
+    let index = if number < 0 { len as i64 + number } else { number - 1 - base as i64 };
+    usize::try_from(index).ok().filter(|index| *index < len)
+}
+
+// history [-c] [-d offset] [n] | history -anrw [filename] | history -ps arg [arg...]
+pub fn history(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, operands)) = parse_options("history", "cd:anprsw", args, err) else {
+        err.push_str(HISTORY_USAGE);
+        return 2;
+    };
+    let flag = |c: char| options.iter().any(|(f, _)| *f == c);
+
+    if flag('c') {
+        let len = shell.editor.history().len();
+        shell.remove_history(0..len);
+    }
+
+    if let Some(position) = options.iter().rev().find_map(|(f, value)| (*f == 'd').then_some(*value).flatten()) {
+        let len = shell.editor.history().len();
+        let base = shell.history_dropped;
+        // A range is `start-end`, either of which may be negative.
+        let separator = position.char_indices().skip(1).find(|&(_, c)| c == '-').map(|(i, _)| i);
+        let range = match separator {
+            Some(i) => history_index(&position[..i], len, base)
+                .zip(history_index(&position[i + 1..], len, base))
+                .filter(|(start, end)| start <= end)
+                .map(|(start, end)| start..end + 1),
+            None => history_index(position, len, base).map(|index| index..index + 1),
+        };
+        let Some(range) = range else {
+            err.push_str(&format!("history: {}: history position out of range\n", position));
+            return 1;
+        };
+        shell.remove_history(range);
+        return 0;
+    }
+
+    let file_options: Vec<char> = "anrw".chars().filter(|c| flag(*c)).collect();
+    if file_options.len() > 1 {
+        err.push_str("history: cannot use more than one of -anrw\n");
+        return 1;
+    }
+    if let Some(&option) = file_options.first() {
+        let Some(path) = operands.first().map(PathBuf::from).or_else(Shell::default_history_path) else {
+            return 1;
+        };
+        let ok = match option {
+            'a' => shell.append_history(&path).is_ok(),
+            'n' => shell.read_new_history(&path).is_ok(),
+            'r' => shell.load_history_file(&path).is_ok(),
+            _ => shell.save_history(&path).is_ok(),
+        };
+        return if ok { 0 } else { 1 };
+    }
+
+    // Neither the `history -p` nor the `history -s` line itself is kept.
+    if flag('p') || (flag('s') && !operands.is_empty()) {
+        shell.remove_current_entry();
+    }
+
+    if flag('p') {
+        let entries: Vec<String> = shell.editor.history().iter().cloned().collect();
+        let mut status = 0;
+        for arg in operands {
+            match crate::history::expand(arg, &entries, shell.history_dropped) {
+                Ok(expansion) => out.push_str(&format!("{}\n", expansion.line)),
+                Err(_) => {
+                    err.push_str(&format!("history: {}: history expansion failed\n", arg));
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    }
+
+    if flag('s') {
+        if operands.is_empty() {
+            return 0;
+        }
+        // Secrets are masked here just as for typed lines.
+        if let Some(line) = shell.redactor().redact(&operands.join(" ")) {
+            shell.push_history(&line, crate::histfile::now());
+        }
+        return 0;
+    }
+
+    if flag('c') {
+        return 0;
+    }
+
+    let history = shell.editor.history();
+    let limit = match operands {
+        [] => history.len(),
+        [count] => match count.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                err.push_str(&format!("history: {}: numeric argument required\n", count));
+                return 1;
+            }
+        },
+        _ => {
+            err.push_str("history: too many arguments\n");
+            return 1;
+        }
+    };
+    let time_format = shell.vars.get("HISTTIMEFORMAT");
+    let start = history.len().saturating_sub(limit);
+    for (i, (entry, time)) in history.iter().zip(&shell.history_times).enumerate().skip(start) {
+        let time = time_format.map(|format| crate::histfile::format_time(format, *time)).unwrap_or_default();
+        out.push_str(&format!("{:5}  {}{}\n", shell.history_dropped + i + 1, time, entry));
+    }
+    0
+}
+
+fn format_duration(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{}ms", ms),
+        1000..=59_999 => format!("{:.1}s", ms as f64 / 1000.0),
+        _ => format!("{}m{}s", ms / 60_000, ms % 60_000 / 1000),
+    }
+}
+
+// histquery [-j] [-d dir] [-s status] [-a time] [-b time] [-n count] [text ...]
+pub fn histquery(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    let Some((options, words)) = parse_options("histquery", "a:b:d:jn:s:", args, err) else {
+        err.push_str("histquery: usage: histquery [-j] [-d dir] [-s status] [-a time] [-b time] [-n count] [text ...]\n");
+        return 2;
+    };
+    let now = crate::histfile::now();
+    let mut json = false;
+    let mut dir = None;
+    let mut status = None;
+    let mut after = None;
+    let mut before = None;
+    let mut count = None;
+    for (flag, value) in options {
+        let value = value.unwrap_or_default();
+        let invalid = |what: &str, err: &mut String| {
+            err.push_str(&format!("histquery: {}: invalid {}\n", value, what));
+            1
+        };
+        match flag {
+            'j' => json = true,
+            'd' => {
+                let path = if value.starts_with('/') {
+                    PathBuf::from(value)
+                } else {
+                    current_dir(shell, false).unwrap_or_default().join(value)
+                };
+                dir = Some(normalize_logical(&path));
+            }
+            's' => match value.parse::<i32>() {
+                Ok(value) => status = Some(value),
+                Err(_) => return invalid("status", err),
+            },
+            'a' | 'b' => match crate::histdb::parse_time(value, now) {
+                Some(time) if flag == 'a' => after = Some(time),
+                Some(time) => before = Some(time),
+                None => return invalid("time", err),
+            },
+            _ => match value.parse::<usize>() {
+                Ok(value) => count = Some(value),
+                Err(_) => return invalid("count", err),
+            },
+        }
+    }
+    let text = words.join(" ");
+
+    let Some(path) = crate::histdb::path(shell.vars.get("HISTDB")) else {
+        err.push_str("histquery: history store is disabled\n");
+        return 1;
+    };
+    let records = match crate::histdb::load(&path) {
+        Ok(records) => records,
+        Err(e) => {
+            err.push_str(&format!("histquery: {}: {}\n", path.display(), error_message(&e)));
+            return 1;
+        }
+    };
+    let in_dir = |cwd: &str| match &dir {
+        None => true,
+        Some(dir) => Path::new(cwd).starts_with(dir),
+    };
+    let matches: Vec<&crate::histdb::Record> = records
+        .iter()
+        .filter(|record| in_dir(&record.cwd))
+        .filter(|record| status.map_or(true, |status| record.status == status))
+        .filter(|record| after.map_or(true, |after| record.start >= after))
+        .filter(|record| before.map_or(true, |before| record.start < before))
+        .filter(|record| record.command.contains(&text))
+        .collect();
+    let matches = &matches[matches.len().saturating_sub(count.unwrap_or(usize::MAX))..];
+
+    if json {
+        let objects: Vec<String> = matches.iter().map(|record| format!("  {}", crate::histdb::to_json(record))).collect();
+        if objects.is_empty() {
+            out.push_str("[]\n");
+        } else {
+            out.push_str(&format!("[\n{}\n]\n", objects.join(",\n")));
+        }
+        return 0;
+    }
+    if !matches.is_empty() {
+        out.push_str(&format!("{:<19}  {:>8}  {:>6}  {:<20}  COMMAND\n", "TIME", "DURATION", "STATUS", "DIRECTORY"));
+    }
+    for record in matches {
+        out.push_str(&format!(
+            "{:<19}  {:>8}  {:>6}  {:<20}  {}\n",
+            crate::histfile::format_time("%Y-%m-%d %H:%M:%S", record.start),
+            format_duration(record.duration_ms),
+            record.status,
+            record.cwd,
+            record.command,
+        ));
+    }
+    0
+}
+
+// The editor to edit commands in: the shell variable `preferred`, such as
+// FCEDIT for `fc`, then EDITOR, then vi. Shell variables are read, not the
+// environment, so an assignment that is not exported still counts.
+pub fn editor_command(vars: &crate::Variables, preferred: &str) -> String {
+    [preferred, "EDITOR"]
+        .iter()
+        .find_map(|name| vars.get(name).filter(|value| !value.is_empty()))
+        .unwrap_or("vi")
+        .to_string()
+}
+
+const FC_USAGE: &str = "fc: usage: fc [-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]\n";
+
+// Resolves an `fc` history specification to an index into `entries`, the
+// first of which is number `base + 1`: a history number, a negative offset
+// from the end, or the start of a command. Numbers out of range are clamped.
+fn fc_index(spec: &str, entries: &[String], base: usize) -> Option<usize> {
+    if entries.is_empty() {
+        return None;
+    }
+    let last = entries.len() - 1;
+    match spec.parse::<i64>() {
+        Ok(number) if number < 0 => Some(last.saturating_sub((number.unsigned_abs() - 1) as usize)),
+        Ok(0) => Some(last),
+        Ok(number) => Some((number as usize - 1).saturating_sub(base).min(last)),
+        Err(_) => entries.iter().rposition(|entry| entry.starts_with(spec)),
+    }
+}
+
+// Replaces the `fc` line in the history with the commands it runs, as one
+// entry however many lines they take, echoes them and runs them.
+fn fc_run(shell: &mut Shell, commands: &str, err: &mut String) -> i32 {
+    shell.remove_current_entry();
+    let lines: Vec<&str> = commands.lines().filter(|line| !line.trim().is_empty()).collect();
+    if lines.is_empty() {
+        return 0;
+    }
+    let command = lines.join("\n");
+    err.push_str(&format!("{}\n", command));
+    shell.add_history(&command);
+    // The echo has to come out before the commands' own output, which
+    // goes straight to the redirected descriptors.
+    eprint!("{}", err);
+    err.clear();
+    crate::run_command(shell, &command);
+    shell.last_status
+}
+
+// fc [-e ename] [-lnr] [first] [last] | fc -s [pat=rep] [command]
+pub fn fc(shell: &mut Shell, args: &[&str], out: &mut String, err: &mut String) -> i32 {
+    // Negative history offsets are operands, not options.
+    let end = args
+        .iter()
+        .position(|arg| arg.strip_prefix('-').is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())))
+        .unwrap_or(args.len());
+    let Some((options, operands)) = parse_options("fc", "e:lnrs", &args[..end], err) else {
+        err.push_str(FC_USAGE);
+        return 2;
+    };
+    let operands: Vec<&str> = operands.iter().chain(&args[end..]).copied().collect();
+    let flag = |c: char| options.iter().any(|(f, _)| *f == c);
+    let editor = options.iter().rev().find_map(|(f, value)| (*f == 'e').then_some(*value).flatten());
+
+    // The `fc` line itself, if it was stored, is never one of the entries
+    // it works on.
+    let mut entries: Vec<String> = shell.editor.history().iter().cloned().collect();
+    if let Some(index) = shell.current_entry_index() {
+        entries.remove(index);
+    }
+    let base = shell.history_dropped;
+    let resolve = |spec: &str, err: &mut String| {
+        let index = fc_index(spec, &entries, base);
+        if index.is_none() {
+            err.push_str(if entries.is_empty() { "fc: history specification out of range\n" } else { "fc: no command found\n" });
+        }
+        index
+    };
+
+    if flag('s') || editor == Some("-") {
+        let (substitution, rest) = match operands.split_first() {
+            Some((first, rest)) if first.contains('=') => (first.split_once('='), rest),
+            _ => (None, &operands[..]),
+        };
+        let Some(index) = resolve(rest.first().copied().unwrap_or("-1"), err) else {
+            return 1;
+        };
+        let mut command = entries[index].clone();
+        if let Some((old, new)) = substitution.filter(|(old, _)| !old.is_empty()) {
+            command = command.replace(old, new);
+        }
+        return fc_run(shell, &command, err);
+    }
+
+    if operands.len() > 2 {
+        err.push_str(FC_USAGE);
+        return 2;
+    }
+    let listing = flag('l');
+    let default_first = if listing { "-16" } else { "-1" };
+    let first = operands.first().copied().unwrap_or(default_first);
+    let last = operands.get(1).copied().unwrap_or(if listing || operands.is_empty() { "-1" } else { first });
+    let (Some(first), Some(last)) = (resolve(first, err), resolve(last, err)) else {
+        return 1;
+    };
+    let mut selected: Vec<(usize, &String)> = if first <= last {
+        (first..=last).map(|i| (i, &entries[i])).collect()
+    } else {
+        (last..=first).rev().map(|i| (i, &entries[i])).collect()
+    };
+    if flag('r') {
+        selected.reverse();
+    }
+
+    if listing {
+        for (i, entry) in selected {
+            if flag('n') {
+                out.push_str(&format!("\t {}\n", entry));
+            } else {
+                out.push_str(&format!("{}\t {}\n", base + i + 1, entry));
+            }
+        }
+        return 0;
+    }
+
+    let editor = editor.map_or_else(|| editor_command(&shell.vars, "FCEDIT"), str::to_string);
+    let contents: String = selected.iter().map(|(_, entry)| format!("{}\n", entry)).collect();
+    let (path, mut file) = match crate::histfile::temp_file("fc", "") {
+        Ok(created) => created,
+        Err(e) => {
+            err.push_str(&format!("fc: {}\n", error_message(&e)));
+            return 1;
+        }
+    };
+    if let Err(e) = file.write_all(contents.as_bytes()) {
+        let _ = std::fs::remove_file(&path);
+        err.push_str(&format!("fc: {}: {}\n", path.display(), error_message(&e)));
+        return 1;
+    }
+    drop(file);
+    crate::run_command(shell, &format!("{} {}", editor, printf::shell_quote(&path.to_string_lossy())));
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    if shell.last_status != 0 {
+        return shell.last_status;
+    }
+    match edited {
+        Ok(commands) => fc_run(shell, &commands, err),
+        Err(e) => {
+            err.push_str(&format!("fc: {}: {}\n", path.display(), error_message(&e)));
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<String> {
+        ["ls", "echo one", "echo two"].iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn fc_index_counts_negative_numbers_from_the_end() {
+        assert_eq!(fc_index("-1", &entries(), 0), Some(2));
+        assert_eq!(fc_index("-3", &entries(), 0), Some(0));
+        assert_eq!(fc_index("-9", &entries(), 0), Some(0));
+        assert_eq!(fc_index("-9223372036854775808", &entries(), 0), Some(0));
+    }
+
+    #[test]
+    fn fc_index_clamps_history_numbers() {
+        assert_eq!(fc_index("0", &entries(), 0), Some(2));
+        assert_eq!(fc_index("2", &entries(), 0), Some(1));
+        assert_eq!(fc_index("12", &entries(), 10), Some(1));
+        assert_eq!(fc_index("3", &entries(), 10), Some(0));
+        assert_eq!(fc_index("9223372036854775807", &entries(), 0), Some(2));
+    }
+
+    #[test]
+    fn fc_index_finds_the_latest_command_with_a_prefix() {
+        assert_eq!(fc_index("echo", &entries(), 0), Some(2));
+        assert_eq!(fc_index("l", &entries(), 0), Some(0));
+        assert_eq!(fc_index("cat", &entries(), 0), None);
+        assert_eq!(fc_index("-1", &[], 0), None);
+    }
+}
@@ -0,0 +1,275 @@
+// Works out what the word being completed is: a command name, a file or a
+// directory, from the words and operators before it. Also lists the files
+// that can complete a partial path.
+
+use crate::lexer::{self, Token};
+
+#[derive(PartialEq)]
+pub enum Position {
+    Command,
+    File,
+    Directory,
+}
+
+// Words after which the next word is still a command name.
+const COMMAND_PREFIXES: &[&str] = &["sudo", "!", "{"];
+
+// Commands whose arguments are directories.
+const DIRECTORY_COMMANDS: &[&str] = &["cd", "pushd"];
+
+// What kind of word follows `before`, the text of the line up to the
+// start of the word being completed.
+pub fn position(before: &str) -> Position {
+    let Ok(tokens) = lexer::tokenize(before) else {
+        return Position::File;
+    };
+    let mut command_position = true;
+    let mut redirect_target = false;
+    let mut command = None;
+    for token in &tokens {
+        match token {
+            Token::Operator(op) if op.ends_with(['<', '>']) => redirect_target = true,
+            Token::Operator(_) => {
+                command_position = true;
+                redirect_target = false;
+                command = None;
+            }
+            Token::Word(_) if redirect_target => redirect_target = false,
+            Token::Word(word) if command_position => {
+                if crate::variables::parse_assignment(word).is_some() || COMMAND_PREFIXES.contains(&word.as_str()) {
+                    continue;
+                }
+                command_position = false;
+                command = Some(word.as_str());
+            }
+            Token::Word(_) => {}
+        }
+    }
+    if redirect_target {
+        Position::File
+    } else if command_position {
+        Position::Command
+    } else if command.is_some_and(|command| DIRECTORY_COMMANDS.contains(&command)) {
+        Position::Directory
+    } else {
+        Position::File
+    }
+}
+
+// A completion before it is quoted to fit the line.
+pub struct Candidate {
+    // Text typed at the start of the word that stays as it is, such as a
+    // `$HOME` to be expanded when the command runs.
+    pub literal: String,
+    // What the rest of the word should expand to.
+    pub value: String,
+    // What the list of completions shows.
+    pub display: String,
+    pub is_dir: bool,
+}
+
+// A directory entry, as completion sees it.
+#[derive(Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+// Lists a directory. Symlinks are followed, so a link to a directory
+// counts as one.
+pub fn list_dir(dir: &str) -> Option<Vec<Entry>> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    let entries = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| Entry { name: entry.file_name().to_string_lossy().into_owned(), is_dir: entry.path().is_dir() })
+        .collect();
+    Some(entries)
+}
+
+// The files whose paths start with `word`, an unquoted value. `list`
+// lists a directory, as list_dir does, and `var` gives the value of a
+// variable. A leading `$NAME/` or `${NAME}/` is looked up to find the
+// directory but kept as typed; if `var` has no value for it, it is taken
+// literally.
+pub fn files(
+    word: &str,
+    directories_only: bool,
+    list: &dyn Fn(&str) -> Option<Vec<Entry>>,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Vec<Candidate> {
+    let (literal, expansion, word) = match variable_prefix(word).and_then(|(literal, name, rest)| Some((literal, var(name)?, rest))) {
+        Some(split) => split,
+        None => ("", String::new(), word),
+    };
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let search = match dir {
+        _ if !literal.is_empty() => format!("{}{}", expansion, dir),
+        "" => ".".to_string(),
+        _ if dir.starts_with("~/") => match std::env::var("HOME") {
+            Ok(home) => format!("{}{}", home, &dir[1..]),
+            Err(_) => return Vec::new(),
+        },
+        _ => dir.to_string(),
+    };
+    let Some(entries) = list(&search) else {
+        return Vec::new();
+    };
+    let mut candidates = Vec::new();
+    for Entry { name, is_dir } in entries {
+        // Hidden files only when asked for with a leading dot.
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        if directories_only && !is_dir {
+            continue;
+        }
+        let display = if is_dir { format!("{}/", name) } else { name.clone() };
+        candidates.push(Candidate { literal: literal.to_string(), value: format!("{}{}", dir, name), display, is_dir });
+    }
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
+
+// Splits a word starting `$NAME/` or `${NAME}/` into the variable
+// reference, the name and the rest, from the `/` on.
+fn variable_prefix(word: &str) -> Option<(&str, &str, &str)> {
+    let after = word.strip_prefix('$')?;
+    let (name, end) = match after.strip_prefix('{') {
+        Some(braced) => {
+            let close = braced.find('}')?;
+            (&braced[..close], close + 3)
+        }
+        None => {
+            let len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            (&after[..len], len + 1)
+        }
+    };
+    if !crate::variables::is_valid_name(name) || !word[end..].starts_with('/') {
+        return None;
+    }
+    Some((&word[..end], name, &word[end..]))
+}
+
+// The text that replaces the word, quoted the way the word was being
+// typed: inside the quote left open, or with special characters escaped.
+// Directories get a trailing `/` and keep the quote open so that
+// completion can carry on into them; anything else is closed off with a
+// space, unless a blank already follows the cursor.
+pub fn replacement(candidate: &Candidate, quote: Option<char>, blank_follows: bool) -> String {
+    let mut text = match quote {
+        Some('\'') => format!("'{}{}", candidate.literal, candidate.value.replace('\'', "'\\''")),
+        Some(quote) => {
+            let mut text = format!("{}{}", quote, candidate.literal);
+            for c in candidate.value.chars() {
+                if matches!(c, '"' | '\\' | '$' | '`') {
+                    text.push('\\');
+                }
+                text.push(c);
+            }
+            text
+        }
+        None => format!("{}{}", candidate.literal, escape(&candidate.value)),
+    };
+    if candidate.is_dir {
+        text.push('/');
+        return text;
+    }
+    text.extend(quote);
+    if !blank_follows {
+        text.push(' ');
+    }
+    text
+}
+
+// Backslash-escapes the characters the shell would otherwise treat
+// specially. A leading `~` is left alone so that it still expands.
+fn escape(value: &str) -> String {
+    let mut text = String::new();
+    for (i, c) in value.char_indices() {
+        let special = c.is_whitespace() || "'\"\\$`&|;<>()*?[]!{}".contains(c) || (c == '#' && i == 0);
+        if special {
+            text.push('\\');
+        }
+        text.push(c);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(literal: &str, value: &str, is_dir: bool) -> Candidate {
+        Candidate { literal: literal.to_string(), value: value.to_string(), display: value.to_string(), is_dir }
+    }
+
+    #[test]
+    fn replacement_quotes_the_way_the_word_was_typed() {
+        let cases = [
+            // (value, quote, expected)
+            ("plain", None, "plain "),
+            ("a b", None, "a\\ b "),
+            ("it's", None, "it\\'s "),
+            ("say \"hi\"", None, "say\\ \\\"hi\\\" "),
+            ("$HOME", None, "\\$HOME "),
+            ("a&b;c", None, "a\\&b\\;c "),
+            ("a b", Some('\''), "'a b' "),
+            ("it's", Some('\''), "'it'\\''s' "),
+            ("$x \"y\"", Some('\''), "'$x \"y\"' "),
+            ("a b", Some('"'), "\"a b\" "),
+            ("it's", Some('"'), "\"it's\" "),
+            ("$x \"y\" `z` \\", Some('"'), "\"\\$x \\\"y\\\" \\`z\\` \\\\\" "),
+        ];
+        for (value, quote, expected) in cases {
+            assert_eq!(replacement(&candidate("", value, false), quote, false), expected, "{:?} {:?}", value, quote);
+        }
+    }
+
+    #[test]
+    fn leading_tilde_expands_and_leading_hash_is_escaped() {
+        let cases = [
+            ("~/notes", "~/notes "),
+            ("a~b", "a~b "),
+            ("#draft", "\\#draft "),
+            ("a#b", "a#b "),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(replacement(&candidate("", value, false), None, false), expected);
+        }
+    }
+
+    #[test]
+    fn directories_end_in_a_slash_and_keep_the_quote_open() {
+        let cases = [
+            (None, "my\\ dir/"),
+            (Some('\''), "'my dir/"),
+            (Some('"'), "\"my dir/"),
+        ];
+        for (quote, expected) in cases {
+            assert_eq!(replacement(&candidate("", "my dir", true), quote, false), expected);
+            assert_eq!(replacement(&candidate("", "my dir", true), quote, true), expected);
+        }
+    }
+
+    #[test]
+    fn a_following_blank_is_not_doubled() {
+        let cases = [
+            (None, "a\\ b"),
+            (Some('\''), "'a b'"),
+            (Some('"'), "\"a b\""),
+        ];
+        for (quote, expected) in cases {
+            assert_eq!(replacement(&candidate("", "a b", false), quote, true), expected);
+        }
+    }
+
+    #[test]
+    fn literal_prefix_is_kept_as_typed() {
+        assert_eq!(replacement(&candidate("$HOME/", "a b", false), None, false), "$HOME/a\\ b ");
+        assert_eq!(replacement(&candidate("$HOME/", "a b", true), Some('"'), false), "\"$HOME/a b/");
+        assert_eq!(replacement(&candidate("${D}/", "it's", false), Some('\''), false), "'${D}/it'\\''s' ");
+    }
+}
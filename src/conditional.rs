@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use regex::Regex;
+
+use crate::arith;
+use crate::expand;
+use crate::glob;
+use crate::variables::{parse_name_with_subscript, Value};
+use crate::Shell;
+
+// Conditional expressions, as evaluated by `test`, `[` and `[[ ... ]]`.
+//
+// `test` gets its arguments already expanded and follows the POSIX rules
+// for deciding what is an operator. `[[` gets the raw words, so it can
+// expand operands lazily and without word splitting or globbing, and tell
+// an unquoted operator from a quoted string.
+
+const UNARY_OPERATORS: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-p", "-r", "-s", "-t", "-u", "-v", "-w",
+    "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+const BINARY_OPERATORS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+fn is_unary(op: &str) -> bool {
+    UNARY_OPERATORS.contains(&op)
+}
+
+fn is_binary(op: &str) -> bool {
+    BINARY_OPERATORS.contains(&op)
+}
+
+fn access(path: &str, mode: libc::c_int) -> bool {
+    match CString::new(path) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+fn unary(shell: &Shell, op: &str, operand: &str) -> bool {
+    match op {
+        "-z" => return operand.is_empty(),
+        "-n" => return !operand.is_empty(),
+        "-v" => {
+            return match parse_name_with_subscript(operand) {
+                Some((name, Some(key))) => shell.vars.get_element(name, key).is_some(),
+                Some((name, None)) => shell.vars.get_var(name).is_some(),
+                None => false,
+            };
+        }
+        "-t" => return operand.parse::<i32>().is_ok_and(|fd| unsafe { libc::isatty(fd) == 1 }),
+        "-r" => return access(operand, libc::R_OK),
+        "-w" => return access(operand, libc::W_OK),
+        "-x" => return access(operand, libc::X_OK),
+        "-h" | "-L" => return fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        _ => {}
+    }
+
+    let Ok(metadata) = fs::metadata(operand) else {
+        return false;
+    };
+    let file_type = metadata.file_type();
+    match op {
+        "-a" | "-e" => true,
+        "-f" => file_type.is_file(),
+        "-d" => file_type.is_dir(),
+        "-b" => file_type.is_block_device(),
+        "-c" => file_type.is_char_device(),
+        "-p" => file_type.is_fifo(),
+        "-S" => file_type.is_socket(),
+        "-s" => metadata.len() > 0,
+        "-g" => metadata.mode() & 0o2000 != 0,
+        "-u" => metadata.mode() & 0o4000 != 0,
+        "-k" => metadata.mode() & 0o1000 != 0,
+        "-O" => metadata.uid() == unsafe { libc::geteuid() },
+        "-G" => metadata.gid() == unsafe { libc::getegid() },
+        "-N" => metadata.mtime() > metadata.atime(),
+        _ => false,
+    }
+}
+
+// `-nt`, `-ot` and `-ef`. A file that exists is newer than one that does not.
+fn compare_files(op: &str, left: &str, right: &str) -> bool {
+    let (left, right) = (fs::metadata(left).ok(), fs::metadata(right).ok());
+    let mtime = |m: &fs::Metadata| (m.mtime(), m.mtime_nsec());
+    match (op, left, right) {
+        ("-nt", Some(l), Some(r)) => mtime(&l) > mtime(&r),
+        ("-nt", Some(_), None) => true,
+        ("-ot", Some(l), Some(r)) => mtime(&l) < mtime(&r),
+        ("-ot", None, Some(_)) => true,
+        ("-ef", Some(l), Some(r)) => l.dev() == r.dev() && l.ino() == r.ino(),
+        _ => false,
+    }
+}
+
+fn compare_integers(op: &str, left: i64, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn parse_integer(text: &str) -> Result<i64, String> {
+    text.trim().parse().map_err(|_| format!("{}: integer expression expected", text))
+}
+
+fn binary(op: &str, left: &str, right: &str) -> Result<bool, String> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-nt" | "-ot" | "-ef" => compare_files(op, left, right),
+        _ => compare_integers(op, parse_integer(left)?, parse_integer(right)?),
+    })
+}
+
+// Evaluates the arguments of `test` (or `[` without its closing bracket).
+pub fn test(shell: &Shell, args: &[String]) -> Result<bool, String> {
+    let arg = |i: usize| args[i].as_str();
+    // With up to four arguments the meaning depends only on the count.
+    match args.len() {
+        0 => return Ok(false),
+        1 => return Ok(!arg(0).is_empty()),
+        2 if arg(0) == "!" => return Ok(arg(1).is_empty()),
+        2 if is_unary(arg(0)) => return Ok(unary(shell, arg(0), arg(1))),
+        2 => return Err(format!("{}: unary operator expected", arg(0))),
+        3 if is_binary(arg(1)) => return binary(arg(1), arg(0), arg(2)),
+        3 if arg(0) == "!" => return test(shell, &args[1..]).map(|result| !result),
+        3 if arg(0) == "(" && arg(2) == ")" => return Ok(!arg(1).is_empty()),
+        4 if arg(0) == "!" => return test(shell, &args[1..]).map(|result| !result),
+        4 if arg(0) == "(" && arg(3) == ")" => return test(shell, &args[1..3]),
+        _ => {}
+    }
+
+    let mut parser = TestParser { shell, args, pos: 0 };
+    let result = parser.or()?;
+    match args.get(parser.pos) {
+        Some(extra) if args.len() == 3 => Err(format!("{}: binary operator expected", extra)),
+        Some(_) => Err("too many arguments".to_string()),
+        None => Ok(result),
+    }
+}
+
+struct TestParser<'a> {
+    shell: &'a Shell,
+    args: &'a [String],
+    pos: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.args.get(self.pos + offset).map(|arg| arg.as_str())
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.pos += 1;
+            let right = self.and()?;
+            result = result || right;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.pos += 1;
+            let right = self.not()?;
+            result = result && right;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") {
+            self.pos += 1;
+            return self.not().map(|result| !result);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let Some(first) = self.peek(0) else {
+            return Err("argument expected".to_string());
+        };
+        if first == "(" && self.peek(1).is_some() {
+            self.pos += 1;
+            let result = self.or()?;
+            if self.peek(0) != Some(")") {
+                return Err("`)' expected".to_string());
+            }
+            self.pos += 1;
+            return Ok(result);
+        }
+        if let (Some(op), Some(right)) = (self.peek(1), self.peek(2)) {
+            if is_binary(op) {
+                self.pos += 3;
+                return binary(op, first, right);
+            }
+        }
+        if let (true, Some(operand)) = (is_unary(first), self.peek(1)) {
+            self.pos += 2;
+            return Ok(unary(self.shell, first, operand));
+        }
+        self.pos += 1;
+        Ok(!first.is_empty())
+    }
+}
+
+// Evaluates the raw words between `[[` and `]]`.
+pub fn conditional(shell: &mut Shell, words: &[String]) -> Result<bool, String> {
+    let mut parser = ConditionalParser { shell, words, pos: 0, evaluate: true };
+    let result = parser.or()?;
+    match words.get(parser.pos) {
+        Some(extra) => Err(format!("syntax error in conditional expression: unexpected token `{}'", extra)),
+        None => Ok(result),
+    }
+}
+
+struct ConditionalParser<'a> {
+    shell: &'a mut Shell,
+    words: &'a [String],
+    pos: usize,
+    // False on the side of `&&` or `||` that is not evaluated, where
+    // operands are parsed but not expanded.
+    evaluate: bool,
+}
+
+impl ConditionalParser<'_> {
+    fn peek(&self, offset: usize) -> Option<&str> {
+        self.words.get(self.pos + offset).map(|word| word.as_str())
+    }
+
+    fn syntax_error(&self) -> String {
+        match self.peek(0) {
+            Some(word) => format!("syntax error in conditional expression: unexpected token `{}'", word),
+            None => "unexpected argument to conditional expression".to_string(),
+        }
+    }
+
+    fn operand(&mut self, word: &str) -> Result<String, String> {
+        if !self.evaluate {
+            return Ok(String::new());
+        }
+        expand::expand_string(self.shell, word)
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("||") {
+            self.pos += 1;
+            let outer = self.evaluate;
+            self.evaluate = outer && !result;
+            let right = self.and()?;
+            self.evaluate = outer;
+            result = result || right;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("&&") {
+            self.pos += 1;
+            let outer = self.evaluate;
+            self.evaluate = outer && result;
+            let right = self.not()?;
+            self.evaluate = outer;
+            result = result && right;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") {
+            self.pos += 1;
+            return self.not().map(|result| !result);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let Some(first) = self.peek(0).map(|word| word.to_string()) else {
+            return Err(self.syntax_error());
+        };
+        if matches!(first.as_str(), "&&" | "||" | ")") {
+            return Err(self.syntax_error());
+        }
+        if first == "(" {
+            self.pos += 1;
+            let result = self.or()?;
+            if self.peek(0) != Some(")") {
+                return Err(self.syntax_error());
+            }
+            self.pos += 1;
+            return Ok(result);
+        }
+
+        if let Some(op) = self.peek(1).filter(|op| is_binary(op) || *op == "=~").map(|op| op.to_string()) {
+            let Some(right) = self.peek(2).map(|word| word.to_string()) else {
+                self.pos += 2;
+                return Err(self.syntax_error());
+            };
+            self.pos += 3;
+            return self.binary(&op, &first, &right);
+        }
+        if is_unary(&first) {
+            if let Some(operand) = self.peek(1).filter(|w| !matches!(*w, "&&" | "||" | ")")).map(|w| w.to_string()) {
+                self.pos += 2;
+                let operand = self.operand(&operand)?;
+                return Ok(self.evaluate && unary(self.shell, &first, &operand));
+            }
+        }
+        self.pos += 1;
+        Ok(!self.operand(&first)?.is_empty())
+    }
+
+    fn binary(&mut self, op: &str, left: &str, right: &str) -> Result<bool, String> {
+        if !self.evaluate {
+            return Ok(false);
+        }
+        let left = self.operand(left)?;
+        match op {
+            // The right-hand side is a pattern; quoted parts match literally.
+            "=" | "==" | "!=" => {
+                let pattern = expand::expand_pattern(self.shell, right)?;
+                Ok(glob::glob_match(&pattern, &left) == (op != "!="))
+            }
+            "=~" => {
+                let pattern = expand::expand_regex(self.shell, right)?;
+                self.regex_match(&pattern, &left)
+            }
+            "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                let right = self.operand(right)?;
+                let left = arith::eval(self.shell, &left)?;
+                let right = arith::eval(self.shell, &right)?;
+                Ok(compare_integers(op, left, right))
+            }
+            _ => {
+                let right = self.operand(right)?;
+                binary(op, &left, &right)
+            }
+        }
+    }
+
+    // Matches an extended regular expression and stores the match and its
+    // groups in BASH_REMATCH.
+    fn regex_match(&mut self, pattern: &str, text: &str) -> Result<bool, String> {
+        let regex = Regex::new(pattern).map_err(|_| format!("{}: invalid regular expression", pattern))?;
+        let mut groups = BTreeMap::new();
+        if let Some(captures) = regex.captures(text) {
+            for (i, group) in captures.iter().enumerate() {
+                groups.insert(i, group.map_or(String::new(), |m| m.as_str().to_string()));
+            }
+        }
+        let matched = !groups.is_empty();
+        self.shell.vars.set_value("BASH_REMATCH", Value::Indexed(groups));
+        Ok(matched)
+    }
+}
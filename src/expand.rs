@@ -0,0 +1,1014 @@
+use crate::arith;
+use crate::builtins;
+use crate::glob;
+use crate::lexer::{self, Token};
+use crate::variables::{is_valid_name, parse_assignment, parse_name_with_subscript};
+use crate::Shell;
+
+// Word expansion: tilde and parameter expansion, word splitting, pathname
+// expansion and quote removal, in that order.
+//
+// A raw word is first turned into a list of pieces that remember how each
+// part of the word was quoted. The pieces are then joined into fields.
+
+#[derive(Debug)]
+enum Piece {
+    // Unquoted text from the command line: glob characters are active.
+    Literal(String),
+    // Quoted text: taken as-is, never split or globbed.
+    Quoted(String),
+    // The result of an unquoted expansion: split on IFS and globbed.
+    Expanded(String),
+    // The elements of `${arr[@]}`, each of which starts a new field.
+    List { items: Vec<String>, quoted: bool },
+}
+
+// The value of a parameter before any operator is applied.
+#[derive(Debug)]
+enum ParamValue {
+    Unset,
+    Scalar(String),
+    // From `[@]` or `[*]`; `star` is set for the `[*]` form.
+    List { items: Vec<String>, star: bool },
+}
+
+#[derive(Debug, Default)]
+struct Field {
+    value: String,
+    pattern: String,
+    glob: bool,
+}
+
+impl Field {
+    fn push_active(&mut self, c: char) {
+        self.value.push(c);
+        self.pattern.push(c);
+        if glob::is_glob_char(c) {
+            self.glob = true;
+        }
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        self.value.push_str(text);
+        self.pattern.push_str(&glob::escape(text));
+    }
+}
+
+// Joins pieces into fields, splitting expansion results on IFS.
+struct FieldBuilder {
+    ifs: String,
+    fields: Vec<Field>,
+    current: Option<Field>,
+    // Set when IFS white space ended the previous field, so that a following
+    // non-white IFS character does not create an extra empty field.
+    split_by_blank: bool,
+}
+
+impl FieldBuilder {
+    fn current(&mut self) -> &mut Field {
+        self.split_by_blank = false;
+        self.current.get_or_insert_with(Field::default)
+    }
+
+    fn finish_field(&mut self) {
+        if let Some(field) = self.current.take() {
+            self.fields.push(field);
+        }
+    }
+
+    fn push_expanded(&mut self, text: &str) {
+        for c in text.chars() {
+            if !self.ifs.contains(c) {
+                self.current().push_active(c);
+            } else if c.is_whitespace() {
+                if let Some(field) = self.current.take() {
+                    self.fields.push(field);
+                    self.split_by_blank = true;
+                }
+            } else {
+                match self.current.take() {
+                    Some(field) => self.fields.push(field),
+                    None if self.split_by_blank => {}
+                    None => self.fields.push(Field::default()),
+                }
+                self.split_by_blank = false;
+            }
+        }
+    }
+
+    fn push(&mut self, piece: Piece) {
+        match piece {
+            Piece::Literal(text) => {
+                let field = self.current();
+                for c in text.chars() {
+                    field.push_active(c);
+                }
+            }
+            Piece::Quoted(text) => self.current().push_quoted(&text),
+            Piece::Expanded(text) => self.push_expanded(&text),
+            Piece::List { items, quoted } => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.finish_field();
+                        self.split_by_blank = false;
+                    }
+                    if quoted {
+                        self.current().push_quoted(item);
+                    } else {
+                        self.push_expanded(item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Expands a single word, which may produce zero or more fields.
+pub fn expand_word(shell: &mut Shell, word: &str) -> Result<Vec<String>, String> {
+    let pieces = scan(shell, word)?;
+    let mut builder = FieldBuilder {
+        ifs: shell.vars.get("IFS").unwrap_or(" \t\n").to_string(),
+        fields: Vec::new(),
+        current: None,
+        split_by_blank: false,
+    };
+    for piece in pieces {
+        builder.push(piece);
+    }
+    builder.finish_field();
+
+    let mut words = Vec::new();
+    for field in builder.fields {
+        if field.glob {
+            let matches = glob::expand_pathname(&field.pattern);
+            if !matches.is_empty() {
+                words.extend(matches);
+                continue;
+            }
+        }
+        words.push(field.value);
+    }
+    Ok(words)
+}
+
+// Expands a word without splitting or pathname expansion, as done for the
+// right-hand side of assignments.
+pub fn expand_string(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let pieces = scan(shell, word)?;
+    Ok(pieces_to_string(pieces))
+}
+
+// Expands a word into a pattern for the matcher. Quoted parts match literally.
+pub fn expand_pattern(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let pieces = scan(shell, word)?;
+    let mut pattern = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Literal(text) | Piece::Expanded(text) => pattern.push_str(&text),
+            Piece::Quoted(text) => pattern.push_str(&glob::escape(&text)),
+            Piece::List { items, quoted: true } => {
+                pattern.push_str(&items.iter().map(|item| glob::escape(item)).collect::<Vec<_>>().join(" "))
+            }
+            Piece::List { items, quoted: false } => pattern.push_str(&items.join(" ")),
+        }
+    }
+    Ok(pattern)
+}
+
+// Expands the right-hand side of `=~` into a regular expression. Quoted
+// parts match literally.
+pub fn expand_regex(shell: &mut Shell, word: &str) -> Result<String, String> {
+    let pieces = scan(shell, word)?;
+    let mut regex = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Literal(text) | Piece::Expanded(text) => regex.push_str(&text),
+            Piece::Quoted(text) => regex.push_str(&regex::escape(&text)),
+            Piece::List { items, quoted: true } => {
+                regex.push_str(&items.iter().map(|item| regex::escape(item)).collect::<Vec<_>>().join(" "))
+            }
+            Piece::List { items, quoted: false } => regex.push_str(&items.join(" ")),
+        }
+    }
+    Ok(regex)
+}
+
+fn pieces_to_string(pieces: Vec<Piece>) -> String {
+    let mut text = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Literal(s) | Piece::Quoted(s) | Piece::Expanded(s) => text.push_str(&s),
+            Piece::List { items, .. } => text.push_str(&items.join(" ")),
+        }
+    }
+    text
+}
+
+// Turns a raw word into pieces.
+fn scan(shell: &mut Shell, word: &str) -> Result<Vec<Piece>, String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+
+    if chars.first() == Some(&'~') {
+        let end = chars.iter().position(|&c| c == '/').unwrap_or(chars.len());
+        let user: String = chars[1..end].iter().collect();
+        if let Some(dir) = tilde_expansion(shell, &user) {
+            pieces.push(Piece::Quoted(dir));
+            i = end;
+        }
+    }
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                match chars.get(i + 1) {
+                    Some(&next) => pieces.push(Piece::Quoted(next.to_string())),
+                    None => pieces.push(Piece::Literal("\\".to_string())),
+                }
+                i += 2;
+            }
+            '\'' => {
+                let end = find_char(&chars, i + 1, '\'')?;
+                pieces.push(Piece::Quoted(chars[i + 1..end].iter().collect()));
+                i = end + 1;
+            }
+            '"' => {
+                let end = find_double_quote_end(&chars, i + 1)?;
+                let inner: String = chars[i + 1..end].iter().collect();
+                pieces.extend(scan_double_quoted(shell, &inner)?);
+                i = end + 1;
+            }
+            '$' => {
+                let (next, value) = expand_dollar(shell, &chars, i, false)?;
+                match value {
+                    Some(expanded) => pieces.extend(expanded),
+                    None => pieces.push(Piece::Literal("$".to_string())),
+                }
+                i = next;
+            }
+            c => {
+                pieces.push(Piece::Literal(c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    Ok(pieces)
+}
+
+// Expands the inside of a double-quoted string. Backslash only escapes
+// the characters that are special between double quotes.
+fn scan_double_quoted(shell: &mut Shell, inner: &str) -> Result<Vec<Piece>, String> {
+    let chars: Vec<char> = inner.chars().collect();
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if matches!(chars.get(i + 1), Some('$' | '`' | '"' | '\\' | '\n')) => {
+                text.push(chars[i + 1]);
+                i += 2;
+            }
+            '$' => {
+                let (next, value) = expand_dollar(shell, &chars, i, true)?;
+                match value {
+                    Some(expanded) => {
+                        pieces.push(Piece::Quoted(std::mem::take(&mut text)));
+                        pieces.extend(expanded);
+                    }
+                    None => text.push('$'),
+                }
+                i = next;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    pieces.push(Piece::Quoted(text));
+
+    // "${arr[@]}" with no elements expands to no word at all, so empty text
+    // around a list must not create a field on its own.
+    if pieces.iter().any(|piece| matches!(piece, Piece::List { .. })) {
+        pieces.retain(|piece| !matches!(piece, Piece::Quoted(text) if text.is_empty()));
+    }
+    Ok(pieces)
+}
+
+fn find_char(chars: &[char], start: usize, target: char) -> Result<usize, String> {
+    chars[start..]
+        .iter()
+        .position(|&c| c == target)
+        .map(|offset| start + offset)
+        .ok_or_else(|| format!("unexpected EOF while looking for matching `{}'", target))
+}
+
+fn find_double_quote_end(chars: &[char], start: usize) -> Result<usize, String> {
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '"' => return Ok(i),
+            '$' if chars.get(i + 1) == Some(&'{') => i = find_brace_end(chars, i + 2)? + 1,
+            _ => i += 1,
+        }
+    }
+    Err("unexpected EOF while looking for matching `\"'".to_string())
+}
+
+// Finds the `}` closing a `${` whose contents start at `start`.
+fn find_brace_end(chars: &[char], start: usize) -> Result<usize, String> {
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '}' => return Ok(i),
+            '\'' => i = find_char(chars, i + 1, '\'')? + 1,
+            '"' => i = find_double_quote_end(chars, i + 1)? + 1,
+            '$' if chars.get(i + 1) == Some(&'{') => i = find_brace_end(chars, i + 2)? + 1,
+            _ => i += 1,
+        }
+    }
+    Err("unexpected EOF while looking for matching `}'".to_string())
+}
+
+fn tilde_expansion(shell: &Shell, user: &str) -> Option<String> {
+    match user {
+        "" => shell.vars.get("HOME").map(|home| home.to_string()),
+        "+" => shell.vars.get("PWD").map(|pwd| pwd.to_string()),
+        "-" => shell.vars.get("OLDPWD").map(|old| old.to_string()),
+        // ~N, ~+N and ~-N: entries of the directory stack.
+        _ => builtins::stack_entry(shell, user),
+    }
+}
+
+// Expands the `$` at `start`. Returns the index after the expansion and
+// the pieces it produced, or None if the `$` is just a literal character.
+fn expand_dollar(
+    shell: &mut Shell,
+    chars: &[char],
+    start: usize,
+    quoted: bool,
+) -> Result<(usize, Option<Vec<Piece>>), String> {
+    let Some(&next) = chars.get(start + 1) else {
+        return Ok((start + 1, None));
+    };
+
+    if next == '{' {
+        let end = find_brace_end(chars, start + 2)?;
+        let content: String = chars[start + 2..end].iter().collect();
+        let pieces = expand_braced(shell, &content, quoted)?;
+        return Ok((end + 1, Some(pieces)));
+    }
+
+    let (name, next_index) = if next.is_ascii_alphabetic() || next == '_' {
+        let len = chars[start + 1..]
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+            .count();
+        (chars[start + 1..start + 1 + len].iter().collect::<String>(), start + 1 + len)
+    } else if is_special_param(next) {
+        (next.to_string(), start + 2)
+    } else {
+        return Ok((start + 1, None));
+    };
+
+    let value = match lookup_value(shell, &name, None)? {
+        ParamValue::Unset => ParamValue::Scalar(String::new()),
+        value => value,
+    };
+    Ok((next_index, Some(emit(shell, value, quoted))))
+}
+
+fn wrap(value: String, quoted: bool) -> Piece {
+    if quoted {
+        Piece::Quoted(value)
+    } else {
+        Piece::Expanded(value)
+    }
+}
+
+// Turns a parameter value into pieces. `"${arr[*]}"` joins the elements
+// with the first character of IFS; every other list form keeps them apart.
+fn emit(shell: &Shell, value: ParamValue, quoted: bool) -> Vec<Piece> {
+    match value {
+        ParamValue::Unset => Vec::new(),
+        ParamValue::Scalar(s) => vec![wrap(s, quoted)],
+        ParamValue::List { items, star: true } if quoted => {
+            let separator = shell.vars.get("IFS").map_or(" ".to_string(), |ifs| ifs.chars().take(1).collect());
+            vec![Piece::Quoted(items.join(&separator))]
+        }
+        ParamValue::List { items, .. } => vec![Piece::List { items, quoted }],
+    }
+}
+
+fn is_special_param(c: char) -> bool {
+    matches!(c, '?' | '$' | '#' | '@' | '*') || c.is_ascii_digit()
+}
+
+fn lookup(shell: &Shell, name: &str) -> Option<String> {
+    match name {
+        "?" => Some(shell.last_status.to_string()),
+        "$" => Some(std::process::id().to_string()),
+        "#" => Some(shell.positional.len().to_string()),
+        "@" | "*" => Some(shell.positional.join(" ")),
+        _ if name.starts_with(|c: char| c.is_ascii_digit()) => {
+            // `${00}` is $0 as well, however many zeros it is written with.
+            match name.parse::<usize>().ok()? {
+                0 => Some(shell.script_name.clone()),
+                index => shell.positional.get(index - 1).cloned(),
+            }
+        }
+        _ => shell.vars.get(name).map(|value| value.to_string()),
+    }
+}
+
+// Evaluates an array subscript: arithmetic for indexed arrays, where a
+// negative index counts back from the end, and a plain string for
+// associative arrays.
+fn eval_subscript(shell: &mut Shell, name: &str, subscript: &str) -> Result<Option<String>, String> {
+    if shell.vars.is_assoc(name) {
+        return expand_string(shell, subscript).map(Some);
+    }
+    let expanded = expand_string(shell, subscript)?;
+    let index = arith::eval(shell, &expanded)?;
+    if index >= 0 {
+        return Ok(Some(index.to_string()));
+    }
+    let keys = shell.vars.keys(name);
+    let next = keys.last().and_then(|k| k.parse::<i64>().ok()).map_or(0, |k| k + 1);
+    if next + index < 0 {
+        return Err(format!("{}[{}]: bad array subscript", name, subscript));
+    }
+    Ok(Some((next + index).to_string()))
+}
+
+fn lookup_value(shell: &mut Shell, name: &str, subscript: Option<&str>) -> Result<ParamValue, String> {
+    match subscript {
+        None if matches!(name, "@" | "*") => Ok(ParamValue::List { items: shell.positional.clone(), star: name == "*" }),
+        None => Ok(lookup(shell, name).map_or(ParamValue::Unset, ParamValue::Scalar)),
+        Some(sub @ ("@" | "*")) => Ok(ParamValue::List { items: shell.vars.values(name), star: sub == "*" }),
+        Some(sub) => {
+            let key = eval_subscript(shell, name, sub)?;
+            let value = key.and_then(|key| shell.vars.get_element(name, &key).map(|v| v.to_string()));
+            Ok(value.map_or(ParamValue::Unset, ParamValue::Scalar))
+        }
+    }
+}
+
+// Splits the start of a `${...}` body into the parameter name, its
+// subscript if any, and the rest.
+fn split_param_name(content: &str) -> (&str, Option<&str>, &str) {
+    let first = content.chars().next();
+    let (name, rest) = match first {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            let len = content
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(content.len());
+            content.split_at(len)
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let len = content.find(|c: char| !c.is_ascii_digit()).unwrap_or(content.len());
+            content.split_at(len)
+        }
+        Some(c) if is_special_param(c) => content.split_at(1),
+        _ => ("", content),
+    };
+
+    if !is_valid_name(name) || !rest.starts_with('[') {
+        return (name, None, rest);
+    }
+    let mut depth = 0;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return (name, Some(&rest[1..i]), &rest[i + 1..]);
+                }
+            }
+            _ => {}
+        }
+    }
+    (name, None, rest)
+}
+
+fn expand_braced(shell: &mut Shell, content: &str, quoted: bool) -> Result<Vec<Piece>, String> {
+    let bad_substitution = || format!("${{{}}}: bad substitution", content);
+
+    // ${#name}: length of the value, ${#arr[@]}: number of elements.
+    if let Some(rest) = content.strip_prefix('#') {
+        if !rest.is_empty() {
+            let (name, subscript, tail) = split_param_name(rest);
+            if name.is_empty() || !tail.is_empty() {
+                return Err(bad_substitution());
+            }
+            let length = match lookup_value(shell, name, subscript)? {
+                ParamValue::Unset => 0,
+                ParamValue::Scalar(value) => value.chars().count(),
+                ParamValue::List { items, .. } => items.len(),
+            };
+            return Ok(vec![wrap(length.to_string(), quoted)]);
+        }
+    }
+
+    let mut indirect = false;
+    let mut body = content;
+    if let Some(rest) = content.strip_prefix('!') {
+        // ${!prefix*} and ${!prefix@}: names of variables with that prefix.
+        if let Some(prefix) = rest.strip_suffix(['*', '@']) {
+            if is_valid_name(prefix) {
+                let names: Vec<String> = shell
+                    .vars
+                    .names()
+                    .into_iter()
+                    .filter(|name| name.starts_with(prefix))
+                    .map(|name| name.to_string())
+                    .collect();
+                let star = rest.ends_with('*');
+                return Ok(emit(shell, ParamValue::List { items: names, star }, quoted));
+            }
+        }
+        // ${!arr[@]} and ${!arr[*]}: the subscripts of an array.
+        if let (name, Some(sub @ ("@" | "*")), "") = split_param_name(rest) {
+            let keys = shell.vars.keys(name);
+            return Ok(emit(shell, ParamValue::List { items: keys, star: sub == "*" }, quoted));
+        }
+        // ${!name}: indirection through the value of `name`.
+        indirect = true;
+        body = rest;
+    }
+
+    let (name, subscript, op) = split_param_name(body);
+    if name.is_empty() {
+        return Err(bad_substitution());
+    }
+    let mut name = name.to_string();
+    let mut subscript = subscript.map(|s| s.to_string());
+    if indirect {
+        let target = match lookup_value(shell, &name, subscript.as_deref())? {
+            ParamValue::Scalar(target) => target,
+            _ => String::new(),
+        };
+        let (target_name, target_subscript, tail) = split_param_name(&target);
+        if target_name.is_empty() || !tail.is_empty() {
+            return Err(format!("{}: invalid indirect expansion", name));
+        }
+        subscript = target_subscript.map(|s| s.to_string());
+        name = target_name.to_string();
+    }
+    let value = lookup_value(shell, &name, subscript.as_deref())?;
+
+    if op.is_empty() {
+        return Ok(emit(shell, value, quoted));
+    }
+
+    // ${name:-word}, ${name:=word}, ${name:?word}, ${name:+word} and the
+    // forms without the colon, which only test for unset.
+    let (check_null, rest) = match op.strip_prefix(':') {
+        Some(rest) if rest.starts_with(['-', '=', '?', '+']) => (true, rest),
+        _ => (false, op),
+    };
+    if let Some(kind) = rest.chars().next().filter(|c| matches!(c, '-' | '=' | '?' | '+')) {
+        let word = &rest[1..];
+        let is_set = match &value {
+            ParamValue::Unset => false,
+            ParamValue::Scalar(v) => !(check_null && v.is_empty()),
+            ParamValue::List { items, .. } => {
+                !(items.is_empty() || check_null && items.iter().all(|item| item.is_empty()))
+            }
+        };
+        return match kind {
+            '-' | '=' | '?' if is_set => Ok(emit(shell, value, quoted)),
+            '-' => scan_operand(shell, word, quoted),
+            '+' if is_set => scan_operand(shell, word, quoted),
+            '+' => Ok(Vec::new()),
+            '=' => {
+                if !is_valid_name(&name) || matches!(subscript.as_deref(), Some("@" | "*")) {
+                    return Err(format!("${}: cannot assign in this way", name));
+                }
+                let assigned = expand_string(shell, word)?;
+                match &subscript {
+                    Some(sub) => assign_element(shell, &name, sub, &assigned, false)?,
+                    None => shell.vars.set(&name, &assigned),
+                }
+                Ok(vec![wrap(assigned, quoted)])
+            }
+            _ => {
+                let message = expand_string(shell, word)?;
+                if message.is_empty() {
+                    let reason = if check_null { "parameter null or not set" } else { "parameter not set" };
+                    Err(format!("{}: {}", name, reason))
+                } else {
+                    Err(format!("{}: {}", name, message))
+                }
+            }
+        };
+    }
+
+    // ${arr[@]:offset:length} slices the list of elements.
+    // For the positional parameters, offset 0 is $0.
+    if let (ParamValue::List { items, star }, Some(spec)) = (&value, op.strip_prefix(':')) {
+        let items = if matches!(name.as_str(), "@" | "*") && subscript.is_none() {
+            std::iter::once(shell.script_name.clone()).chain(items.iter().cloned()).collect()
+        } else {
+            items.clone()
+        };
+        let range = substring_range(shell, items.len(), spec)?;
+        let items = range.map_or(Vec::new(), |(start, end)| items[start..end].to_vec());
+        return Ok(emit(shell, ParamValue::List { items, star: *star }, quoted));
+    }
+
+    let value = match value {
+        ParamValue::List { items, star } => {
+            let mut results = Vec::new();
+            for item in items {
+                results.push(apply_operator(shell, &item, op).ok_or_else(bad_substitution)??);
+            }
+            ParamValue::List { items: results, star }
+        }
+        ParamValue::Scalar(value) => ParamValue::Scalar(apply_operator(shell, &value, op).ok_or_else(bad_substitution)??),
+        ParamValue::Unset => ParamValue::Scalar(apply_operator(shell, "", op).ok_or_else(bad_substitution)??),
+    };
+    Ok(emit(shell, value, quoted))
+}
+
+// Expands the word of a default or alternate value operator. Inside double
+// quotes the whole result stays quoted.
+fn scan_operand(shell: &mut Shell, word: &str, quoted: bool) -> Result<Vec<Piece>, String> {
+    let pieces = scan(shell, word)?;
+    if !quoted {
+        return Ok(pieces);
+    }
+    Ok(vec![Piece::Quoted(pieces_to_string(pieces))])
+}
+
+// Applies a pattern, substitution, case or substring operator to a value.
+// Returns None if `op` is not a known operator.
+fn apply_operator(shell: &mut Shell, value: &str, op: &str) -> Option<Result<String, String>> {
+    let result = if let Some(word) = op.strip_prefix("##") {
+        expand_pattern(shell, word).map(|p| remove_prefix(value, &p, true))
+    } else if let Some(word) = op.strip_prefix('#') {
+        expand_pattern(shell, word).map(|p| remove_prefix(value, &p, false))
+    } else if let Some(word) = op.strip_prefix("%%") {
+        expand_pattern(shell, word).map(|p| remove_suffix(value, &p, true))
+    } else if let Some(word) = op.strip_prefix('%') {
+        expand_pattern(shell, word).map(|p| remove_suffix(value, &p, false))
+    } else if let Some(word) = op.strip_prefix('/') {
+        substitute(shell, value, word)
+    } else if let Some(word) = op.strip_prefix("^^") {
+        expand_pattern(shell, word).map(|p| change_case(value, &p, true, true))
+    } else if let Some(word) = op.strip_prefix('^') {
+        expand_pattern(shell, word).map(|p| change_case(value, &p, true, false))
+    } else if let Some(word) = op.strip_prefix(",,") {
+        expand_pattern(shell, word).map(|p| change_case(value, &p, false, true))
+    } else if let Some(word) = op.strip_prefix(',') {
+        expand_pattern(shell, word).map(|p| change_case(value, &p, false, false))
+    } else if let Some(spec) = op.strip_prefix(':') {
+        substring(shell, value, spec)
+    } else {
+        return None;
+    };
+    Some(result)
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    match glob::match_prefix(pattern, value, longest) {
+        Some(end) => value[end..].to_string(),
+        None => value.to_string(),
+    }
+}
+
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    match glob::match_suffix(pattern, value, longest) {
+        Some(start) => value[..start].to_string(),
+        None => value.to_string(),
+    }
+}
+
+// Handles ${name/pat/rep}, ${name//pat/rep}, ${name/#pat/rep} and ${name/%pat/rep}.
+// `word` is everything after the first slash.
+fn substitute(shell: &mut Shell, value: &str, word: &str) -> Result<String, String> {
+    let (mode, word) = match word.chars().next() {
+        Some('/') => ('/', &word[1..]),
+        Some('#') => ('#', &word[1..]),
+        Some('%') => ('%', &word[1..]),
+        _ => (' ', word),
+    };
+    let (raw_pattern, raw_replacement) = split_unquoted(word, '/');
+    let pattern = expand_pattern(shell, raw_pattern)?;
+    let replacement = match raw_replacement {
+        Some(raw) => expand_string(shell, raw)?,
+        None => String::new(),
+    };
+    // An empty pattern still anchors: `${v/#/P}` prepends and `${v/%/S}` appends.
+    if pattern.is_empty() && !matches!(mode, '#' | '%') {
+        return Ok(value.to_string());
+    }
+
+    let result = match mode {
+        '#' => match glob::match_prefix(&pattern, value, true) {
+            Some(end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        '%' => match glob::match_suffix(&pattern, value, true) {
+            Some(start) => format!("{}{}", &value[..start], replacement),
+            None => value.to_string(),
+        },
+        _ => {
+            let mut result = String::new();
+            let mut rest = value;
+            let mut matched = false;
+            while let Some((start, end)) = glob::find_match(&pattern, rest) {
+                // Once something has matched, an empty match at the very end
+                // would only add the replacement again.
+                if matched && start == end && end == rest.len() {
+                    break;
+                }
+                matched = true;
+                result.push_str(&rest[..start]);
+                result.push_str(&replacement);
+                if end == start {
+                    // An empty match would loop forever; step past one character.
+                    match rest[end..].chars().next() {
+                        Some(c) => {
+                            result.push(c);
+                            rest = &rest[end + c.len_utf8()..];
+                        }
+                        None => {
+                            rest = "";
+                            break;
+                        }
+                    }
+                } else {
+                    rest = &rest[end..];
+                }
+                if mode != '/' {
+                    break;
+                }
+            }
+            result.push_str(rest);
+            result
+        }
+    };
+    Ok(result)
+}
+
+// Splits `word` at the first `separator` that is not quoted or escaped.
+fn split_unquoted(word: &str, separator: char) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in word.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == separator {
+            return (&word[..i], Some(&word[i + c.len_utf8()..]));
+        }
+    }
+    (word, None)
+}
+
+// ${name^pat}, ${name^^pat}, ${name,pat} and ${name,,pat}. An empty pattern
+// matches every character.
+fn change_case(value: &str, pattern: &str, upper: bool, all: bool) -> String {
+    let mut result = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let selected = (all || i == 0) && (pattern.is_empty() || glob::glob_match(pattern, &c.to_string()));
+        if !selected {
+            result.push(c);
+        } else if upper {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+    result
+}
+
+// Works out the range selected by `offset[:length]` over `len` items. A
+// negative offset counts from the end, as does a negative length.
+fn substring_range(shell: &mut Shell, len: usize, spec: &str) -> Result<Option<(usize, usize)>, String> {
+    let (raw_offset, raw_length) = split_unquoted(spec, ':');
+    let len = len as i64;
+
+    let offset_expr = expand_string(shell, raw_offset)?;
+    let mut offset = arith::eval(shell, &offset_expr)?;
+    if offset < 0 {
+        offset += len;
+    }
+    if offset < 0 || offset > len {
+        return Ok(None);
+    }
+
+    let end = match raw_length {
+        Some(raw) => {
+            let length_expr = expand_string(shell, raw)?;
+            let length = arith::eval(shell, &length_expr)?;
+            let end = if length < 0 { len + length } else { offset.saturating_add(length) };
+            if end < offset {
+                return Err(format!("{}: substring expression < 0", raw.trim()));
+            }
+            end.min(len)
+        }
+        None => len,
+    };
+    Ok(Some((offset as usize, end as usize)))
+}
+
+// ${name:offset} and ${name:offset:length}, counted in characters.
+fn substring(shell: &mut Shell, value: &str, spec: &str) -> Result<String, String> {
+    let chars: Vec<char> = value.chars().collect();
+    let range = substring_range(shell, chars.len(), spec)?;
+    Ok(range.map_or(String::new(), |(start, end)| chars[start..end].iter().collect()))
+}
+
+// Sets one array element from an assignment like `arr[sub]=value`.
+pub fn assign_element(shell: &mut Shell, name: &str, subscript: &str, value: &str, append: bool) -> Result<(), String> {
+    if matches!(subscript, "@" | "*") {
+        return Err(format!("{}[{}]: bad array subscript", name, subscript));
+    }
+    let key = eval_subscript(shell, name, subscript)?.unwrap_or_default();
+    let mut value = value.to_string();
+    if append {
+        value = format!("{}{}", shell.vars.get_element(name, &key).unwrap_or(""), value);
+    }
+    if shell.vars.is_assoc(name) {
+        shell.vars.set_key(name, &key, &value);
+    } else {
+        let index = key.parse().map_err(|_| format!("{}[{}]: bad array subscript", name, subscript))?;
+        shell.vars.set_index(name, index, &value);
+    }
+    Ok(())
+}
+
+// Performs an assignment word: `name=value`, `name+=value`,
+// `name[sub]=value` or a compound `name=(a b c)` / `name=([key]=value ...)`.
+pub fn assign(shell: &mut Shell, word: &str) -> Result<(), String> {
+    let assignment = parse_assignment(word).ok_or_else(|| format!("`{}': not a valid identifier", word))?;
+    let name = assignment.name;
+
+    if let Some(subscript) = assignment.subscript {
+        let value = expand_string(shell, assignment.value)?;
+        return assign_element(shell, name, subscript, &value, assignment.append);
+    }
+
+    let compound = assignment
+        .value
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'));
+    let Some(inner) = compound else {
+        let mut value = expand_string(shell, assignment.value)?;
+        if assignment.append {
+            value = format!("{}{}", shell.vars.get(name).unwrap_or(""), value);
+        }
+        shell.vars.set(name, &value);
+        return Ok(());
+    };
+
+    let mut words = Vec::new();
+    for token in lexer::tokenize(inner)? {
+        match token {
+            Token::Word(word) => words.push(word),
+            // Compound values may span several lines.
+            Token::Operator(op) if op == "\n" => {}
+            Token::Operator(op) => return Err(format!("syntax error near unexpected token `{}'", op)),
+        }
+    }
+
+    if !assignment.append {
+        let empty = if shell.vars.is_assoc(name) {
+            crate::variables::Value::Assoc(Default::default())
+        } else {
+            crate::variables::Value::Indexed(Default::default())
+        };
+        shell.vars.set_value(name, empty);
+    } else if !shell.vars.is_assoc(name) {
+        shell.vars.declare_indexed(name)?;
+    }
+
+    let assoc = shell.vars.is_assoc(name);
+    let mut next_index = shell.vars.next_index(name);
+    for word in words {
+        // `[subscript]=value` sets an explicit element.
+        let explicit = word
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("]="))
+            .map(|(sub, value)| (sub.to_string(), value.to_string()));
+        match explicit {
+            Some((subscript, raw_value)) => {
+                let value = expand_string(shell, &raw_value)?;
+                assign_element(shell, name, &subscript, &value, false)?;
+                if !assoc {
+                    let key = eval_subscript(shell, name, &subscript)?.unwrap_or_default();
+                    next_index = key.parse::<usize>().map_or(next_index, |index| index + 1);
+                }
+            }
+            None if assoc => {
+                return Err(format!("{}: {}: must use subscript when assigning associative array", name, word));
+            }
+            None => {
+                for value in expand_word(shell, &word)? {
+                    shell.vars.set_index(name, next_index, &value);
+                    next_index += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Splits an `unset` argument into a name and an optional subscript and
+// removes it.
+pub fn unset(shell: &mut Shell, target: &str) -> Result<(), String> {
+    let (name, subscript) =
+        parse_name_with_subscript(target).ok_or_else(|| format!("`{}': not a valid identifier", target))?;
+    match subscript {
+        None | Some("@" | "*") => shell.vars.unset(name),
+        Some(sub) => {
+            if let Some(key) = eval_subscript(shell, name, sub)? {
+                shell.vars.unset_element(name, &key);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substring_range_clamps_a_huge_length() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(substring_range(&mut shell, 3, "1:9223372036854775807"), Ok(Some((1, 3))));
+        assert_eq!(substring_range(&mut shell, 3, "2:9223372036854775807"), Ok(Some((2, 3))));
+        assert_eq!(
+            substring_range(&mut shell, 3, "0:-9223372036854775807"),
+            Err("-9223372036854775807: substring expression < 0".to_string())
+        );
+    }
+
+    #[test]
+    fn substring_range_counts_negatives_from_the_end() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(substring_range(&mut shell, 5, "-2"), Ok(Some((3, 5))));
+        assert_eq!(substring_range(&mut shell, 5, "1:-1"), Ok(Some((1, 4))));
+        assert_eq!(substring_range(&mut shell, 5, "6"), Ok(None));
+        assert_eq!(substring_range(&mut shell, 5, "-9"), Ok(None));
+    }
+
+    #[test]
+    fn substitute_replaces_a_match_that_takes_the_whole_value_once() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(substitute(&mut shell, "aaa", "/*/x"), Ok("x".to_string()));
+        assert_eq!(substitute(&mut shell, "aaa", "*/x"), Ok("x".to_string()));
+        assert_eq!(substitute(&mut shell, "abab", "/b/X"), Ok("aXaX".to_string()));
+        assert_eq!(substitute(&mut shell, "abab", "b/X"), Ok("aXab".to_string()));
+    }
+
+    #[test]
+    fn substitute_anchors_an_empty_pattern() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(substitute(&mut shell, "aaa", "#/P"), Ok("Paaa".to_string()));
+        assert_eq!(substitute(&mut shell, "aaa", "%/S"), Ok("aaaS".to_string()));
+        assert_eq!(substitute(&mut shell, "aaa", "/x"), Ok("aaa".to_string()));
+        assert_eq!(substitute(&mut shell, "aaa", "//x"), Ok("aaa".to_string()));
+    }
+
+    #[test]
+    fn substitute_anchored_patterns() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(substitute(&mut shell, "abcabc", "#a*/X"), Ok("X".to_string()));
+        assert_eq!(substitute(&mut shell, "abcabc", "%c/X"), Ok("abcabX".to_string()));
+        assert_eq!(substitute(&mut shell, "abcabc", "#b/X"), Ok("abcabc".to_string()));
+    }
+
+    #[test]
+    fn any_number_of_zeros_names_the_script() {
+        let mut shell = Shell::for_tests();
+        shell.script_name = "script".to_string();
+        shell.positional = vec!["one".to_string()];
+        assert_eq!(lookup(&shell, "0"), Some("script".to_string()));
+        assert_eq!(lookup(&shell, "00"), Some("script".to_string()));
+        assert_eq!(lookup(&shell, "01"), Some("one".to_string()));
+        assert_eq!(lookup(&shell, "2"), None);
+    }
+
+    #[test]
+    fn unset_required_parameter_is_an_error() {
+        let mut shell = Shell::for_tests();
+        shell.vars.unset("u");
+        assert_eq!(expand_string(&mut shell, "${u:?oops}"), Err("u: oops".to_string()));
+    }
+}
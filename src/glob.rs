@@ -0,0 +1,434 @@
+use std::path::Path;
+
+// Shell pattern matching, shared by pathname expansion and the pattern
+// operators of parameter expansion. A backslash makes the next character
+// literal, which is how quoted parts of a word reach the matcher.
+
+#[derive(Debug)]
+enum PatternToken {
+    Char(char),
+    Any,
+    Star,
+    Class { negated: bool, items: Vec<ClassItem> },
+}
+
+#[derive(Debug)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Named(String),
+}
+
+pub fn is_glob_char(c: char) -> bool {
+    matches!(c, '*' | '?' | '[')
+}
+
+// Returns true if the pattern contains an unescaped glob character.
+pub fn has_glob_chars(pattern: &str) -> bool {
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if is_glob_char(c) {
+            return true;
+        }
+    }
+    false
+}
+
+// Escapes every character the matcher would otherwise treat specially.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Removes the escaping backslashes from a pattern, giving back the literal text.
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if escaped {
+            text.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else {
+            text.push(c);
+        }
+    }
+    if escaped {
+        text.push('\\');
+    }
+    text
+}
+
+fn compile(pattern: &str) -> Vec<PatternToken> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(PatternToken::Char(chars[i + 1]));
+                i += 2;
+            }
+            '*' => {
+                // Consecutive stars behave like a single one.
+                if !matches!(tokens.last(), Some(PatternToken::Star)) {
+                    tokens.push(PatternToken::Star);
+                }
+                i += 1;
+            }
+            '?' => {
+                tokens.push(PatternToken::Any);
+                i += 1;
+            }
+            '[' => match compile_class(&chars, i) {
+                Some((token, next)) => {
+                    tokens.push(token);
+                    i = next;
+                }
+                None => {
+                    tokens.push(PatternToken::Char('['));
+                    i += 1;
+                }
+            },
+            c => {
+                tokens.push(PatternToken::Char(c));
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+// Parses a bracket expression starting at `start` (the '['). Returns None if
+// it is not terminated, in which case the '[' is matched literally.
+fn compile_class(chars: &[char], start: usize) -> Option<(PatternToken, usize)> {
+    let mut i = start + 1;
+    let mut negated = false;
+    if i < chars.len() && (chars[i] == '!' || chars[i] == '^') {
+        negated = true;
+        i += 1;
+    }
+
+    let mut items = Vec::new();
+    let mut first = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ']' && !first {
+            return Some((PatternToken::Class { negated, items }, i + 1));
+        }
+        first = false;
+
+        if c == '[' && chars.get(i + 1) == Some(&':') {
+            let rest: String = chars[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                items.push(ClassItem::Named(rest[..end].to_string()));
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+
+        let mut low = c;
+        if c == '\\' && i + 1 < chars.len() {
+            i += 1;
+            low = chars[i];
+        }
+        if chars.get(i + 1) == Some(&'-') && i + 2 < chars.len() && chars[i + 2] != ']' {
+            let mut high_index = i + 2;
+            if chars[high_index] == '\\' && high_index + 1 < chars.len() {
+                high_index += 1;
+            }
+            items.push(ClassItem::Range(low, chars[high_index]));
+            i = high_index + 1;
+        } else {
+            items.push(ClassItem::Char(low));
+            i += 1;
+        }
+    }
+    None
+}
+
+fn class_matches(negated: bool, items: &[ClassItem], c: char) -> bool {
+    let found = items.iter().any(|item| match item {
+        ClassItem::Char(x) => *x == c,
+        ClassItem::Range(low, high) => *low <= c && c <= *high,
+        ClassItem::Named(name) => match name.as_str() {
+            "alpha" => c.is_alphabetic(),
+            "digit" => c.is_ascii_digit(),
+            "alnum" => c.is_alphanumeric(),
+            "upper" => c.is_uppercase(),
+            "lower" => c.is_lowercase(),
+            "space" => c.is_whitespace(),
+            "blank" => c == ' ' || c == '\t',
+            "punct" => c.is_ascii_punctuation(),
+            "xdigit" => c.is_ascii_hexdigit(),
+            "cntrl" => c.is_control(),
+            "print" => !c.is_control(),
+            "graph" => !c.is_control() && !c.is_whitespace(),
+            "word" => c.is_alphanumeric() || c == '_',
+            _ => false,
+        },
+    });
+    found != negated
+}
+
+fn token_matches(token: &PatternToken, c: char) -> bool {
+    match token {
+        PatternToken::Char(x) => *x == c,
+        PatternToken::Any => true,
+        PatternToken::Class { negated, items } => class_matches(*negated, items, c),
+        PatternToken::Star => true,
+    }
+}
+
+fn matches_tokens(tokens: &[PatternToken], text: &[char]) -> bool {
+    let (mut t, mut s) = (0, 0);
+    // Position to resume from when a star has to swallow one more character.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < text.len() {
+        match tokens.get(t) {
+            Some(PatternToken::Star) => {
+                backtrack = Some((t, s));
+                t += 1;
+            }
+            Some(token) if token_matches(token, text[s]) => {
+                t += 1;
+                s += 1;
+            }
+            _ => match backtrack {
+                Some((star, consumed)) => {
+                    t = star + 1;
+                    s = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[t..].iter().all(|token| matches!(token, PatternToken::Star))
+}
+
+// Matches the whole of `text` against a shell pattern.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let tokens = compile(pattern);
+    let chars: Vec<char> = text.chars().collect();
+    matches_tokens(&tokens, &chars)
+}
+
+// Finds where `tokens` match in `text` and returns the match as a range of
+// char indices: anchored to the start of the text, or else the leftmost
+// one. The states are the token positions reached so far, each with the
+// earliest index a match through it started at, so the text is read once
+// whatever the stars.
+fn find_tokens(tokens: &[&PatternToken], text: impl Iterator<Item = char>, anchored: bool, longest: bool) -> Option<(usize, usize)> {
+    // Reaching a star also reaches whatever follows it, since it may match nothing.
+    let close = |states: &mut Vec<Option<usize>>| {
+        for t in 0..tokens.len() {
+            if let (Some(start), PatternToken::Star) = (states[t], tokens[t]) {
+                states[t + 1] = Some(states[t + 1].map_or(start, |other| other.min(start)));
+            }
+        }
+    };
+    let mut states = vec![None; tokens.len() + 1];
+    let mut found: Option<(usize, usize)> = None;
+    let mut text = text;
+    let mut index = 0;
+    loop {
+        // A later start can only give a match further right.
+        if index == 0 || (!anchored && found.is_none()) {
+            states[0] = Some(states[0].map_or(index, |start: usize| start.min(index)));
+        }
+        close(&mut states);
+        if let Some(start) = states[tokens.len()] {
+            if found.map_or(true, |(first, _)| start < first || (start == first && longest)) {
+                found = Some((start, index));
+            }
+            if !longest {
+                return found;
+            }
+        }
+        if let Some((first, _)) = found {
+            for state in states.iter_mut() {
+                *state = state.filter(|&start| start <= first);
+            }
+        }
+        if states.iter().all(Option::is_none) && (anchored || found.is_some()) {
+            break;
+        }
+        let Some(c) = text.next() else {
+            break;
+        };
+        let mut next = vec![None; tokens.len() + 1];
+        for t in 0..tokens.len() {
+            let Some(start) = states[t] else {
+                continue;
+            };
+            let to = match tokens[t] {
+                PatternToken::Star => t,
+                token if token_matches(token, c) => t + 1,
+                _ => continue,
+            };
+            next[to] = Some(next[to].map_or(start, |other: usize| other.min(start)));
+        }
+        states = next;
+        index += 1;
+    }
+    found
+}
+
+// Byte offset of the char at `index` in `text`, or its length past the end.
+fn byte_offset(text: &str, index: usize) -> usize {
+    text.char_indices().nth(index).map_or(text.len(), |(i, _)| i)
+}
+
+// Length in bytes of the shortest (or longest) prefix of `text` matching `pattern`.
+pub fn match_prefix(pattern: &str, text: &str, longest: bool) -> Option<usize> {
+    let tokens = compile(pattern);
+    let tokens: Vec<&PatternToken> = tokens.iter().collect();
+    let (_, end) = find_tokens(&tokens, text.chars(), true, longest)?;
+    Some(byte_offset(text, end))
+}
+
+// Byte offset where the shortest (or longest) suffix of `text` matching
+// `pattern` starts. The pattern is matched backwards from the end.
+pub fn match_suffix(pattern: &str, text: &str, longest: bool) -> Option<usize> {
+    let tokens = compile(pattern);
+    let tokens: Vec<&PatternToken> = tokens.iter().rev().collect();
+    let (_, len) = find_tokens(&tokens, text.chars().rev(), true, longest)?;
+    Some(byte_offset(text, text.chars().count() - len))
+}
+
+// Finds the first position where `pattern` matches, preferring the longest
+// match there. Returns the byte range of the match.
+pub fn find_match(pattern: &str, text: &str) -> Option<(usize, usize)> {
+    let tokens = compile(pattern);
+    let tokens: Vec<&PatternToken> = tokens.iter().collect();
+    let (start, end) = find_tokens(&tokens, text.chars(), false, true)?;
+    Some((byte_offset(text, start), byte_offset(text, end)))
+}
+
+// Expands a pathname pattern into the sorted list of existing paths it
+// matches. Returns an empty list when nothing matches.
+pub fn expand_pathname(pattern: &str) -> Vec<String> {
+    let absolute = pattern.starts_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+
+    let mut paths = vec![if absolute { "/".to_string() } else { String::new() }];
+    for (index, component) in components.iter().enumerate() {
+        let is_last = index == components.len() - 1;
+        let mut next = Vec::new();
+
+        for base in &paths {
+            if !has_glob_chars(component) {
+                let candidate = format!("{}{}", base, unescape(component));
+                if is_last || Path::new(&candidate).is_dir() {
+                    next.push(candidate);
+                }
+                continue;
+            }
+
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|name| !name.starts_with('.') || component.starts_with('.') || component.starts_with("\\."))
+                .filter(|name| glob_match(component, name))
+                .collect();
+            names.sort();
+
+            for name in names {
+                let candidate = format!("{}{}", base, name);
+                if is_last || Path::new(&candidate).is_dir() {
+                    next.push(candidate);
+                }
+            }
+        }
+
+        if !is_last {
+            for path in next.iter_mut() {
+                path.push('/');
+            }
+        }
+        paths = next;
+    }
+
+    if pattern.ends_with('/') {
+        paths.retain(|p| Path::new(p).is_dir());
+        for path in paths.iter_mut() {
+            path.push('/');
+        }
+    }
+
+    // A pattern like "*/" leaves the trailing separator on each match already.
+    paths.retain(|p| !p.is_empty() && Path::new(p).exists());
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str, anchored: bool, longest: bool) -> Option<(usize, usize)> {
+        let tokens = compile(pattern);
+        let tokens: Vec<&PatternToken> = tokens.iter().collect();
+        find_tokens(&tokens, text.chars(), anchored, longest)
+    }
+
+    #[test]
+    fn find_tokens_takes_the_leftmost_match() {
+        assert_eq!(find("b*", "abcb", false, true), Some((1, 4)));
+        assert_eq!(find("b*", "abcb", false, false), Some((1, 2)));
+        assert_eq!(find("c", "abcb", false, true), Some((2, 3)));
+        assert_eq!(find("x", "abcb", false, true), None);
+        assert_eq!(find("*", "", false, true), Some((0, 0)));
+        assert_eq!(find("", "abc", false, true), Some((0, 0)));
+    }
+
+    #[test]
+    fn find_tokens_anchored_only_matches_at_the_start() {
+        assert_eq!(find("b", "abc", true, true), None);
+        assert_eq!(find("a?", "abc", true, true), Some((0, 2)));
+        assert_eq!(find("a*c", "abcabc", true, false), Some((0, 3)));
+        assert_eq!(find("a*c", "abcabc", true, true), Some((0, 6)));
+    }
+
+    #[test]
+    fn match_prefix_shortest_and_longest() {
+        assert_eq!(match_prefix("*b", "abab", false), Some(2));
+        assert_eq!(match_prefix("*b", "abab", true), Some(4));
+        assert_eq!(match_prefix("b", "abab", true), None);
+        assert_eq!(match_prefix("", "abab", true), Some(0));
+        assert_eq!(match_prefix("[a-z]é", "aé!", true), Some(3));
+    }
+
+    #[test]
+    fn match_suffix_shortest_and_longest() {
+        assert_eq!(match_suffix("a*", "abab", false), Some(2));
+        assert_eq!(match_suffix("a*", "abab", true), Some(0));
+        assert_eq!(match_suffix("a", "abab", true), None);
+        assert_eq!(match_suffix("", "abab", true), Some(4));
+        assert_eq!(match_suffix("é?", "aéb", true), Some(1));
+    }
+
+    #[test]
+    fn find_match_returns_byte_offsets() {
+        assert_eq!(find_match("b*", "ébcb"), Some((2, 5)));
+        assert_eq!(find_match("\\*", "a*b"), Some((1, 2)));
+        assert_eq!(find_match("[!a]", "aab"), Some((2, 3)));
+    }
+}
@@ -0,0 +1,480 @@
+// Colours the line being typed. Command words are coloured by whether they
+// resolve to something that can run; strings, variables, operators,
+// redirections and comments each get their own colour; words naming files
+// that exist are underlined; and the bracket or quote under the cursor is
+// shown with its partner. The scan follows the lexer's rules but never
+// fails, since the line is usually unfinished while it is being typed.
+
+use std::path::Path;
+
+// SGR parameters for each kind of text, from HIGHLIGHT_COLORS: a list of
+// `name=parameters` separated by colons, such as `command=1;32:comment=2`.
+// An empty value leaves that kind uncoloured.
+pub struct Palette {
+    command: String,
+    error: String,
+    string: String,
+    variable: String,
+    operator: String,
+    redirect: String,
+    comment: String,
+    path: String,
+    matching: String,
+    hint: String,
+}
+
+impl Palette {
+    pub fn new(spec: Option<&str>) -> Self {
+        let mut palette = Palette {
+            command: "32".to_string(),
+            error: "31".to_string(),
+            string: "33".to_string(),
+            variable: "36".to_string(),
+            operator: "35".to_string(),
+            redirect: "35".to_string(),
+            comment: "90".to_string(),
+            path: "4".to_string(),
+            matching: "1;34".to_string(),
+            hint: "90".to_string(),
+        };
+        for item in spec.unwrap_or_default().split(':') {
+            let Some((name, value)) = item.split_once('=') else {
+                continue;
+            };
+            // Only SGR parameters, so that a value cannot smuggle in other
+            // escape sequences.
+            if !value.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                continue;
+            }
+            let field = match name {
+                "command" => &mut palette.command,
+                "error" => &mut palette.error,
+                "string" => &mut palette.string,
+                "variable" => &mut palette.variable,
+                "operator" => &mut palette.operator,
+                "redirect" => &mut palette.redirect,
+                "comment" => &mut palette.comment,
+                "path" => &mut palette.path,
+                "matching" => &mut palette.matching,
+                "hint" => &mut palette.hint,
+                _ => continue,
+            };
+            *field = value.to_string();
+        }
+        palette
+    }
+
+    // A suggestion shown after the cursor.
+    pub fn hint(&self, hint: &str) -> String {
+        if self.hint.is_empty() {
+            return hint.to_string();
+        }
+        format!("\x1b[{}m{}\x1b[0m", self.hint, hint)
+    }
+
+    fn color(&self, kind: Kind) -> &str {
+        match kind {
+            Kind::Plain => "",
+            Kind::Command => &self.command,
+            Kind::Error => &self.error,
+            Kind::String => &self.string,
+            Kind::Variable => &self.variable,
+            Kind::Operator => &self.operator,
+            Kind::Redirect => &self.redirect,
+            Kind::Comment => &self.comment,
+        }
+    }
+}
+
+// Whether the line should be coloured at all: not on a dumb terminal, and
+// not when NO_COLOR is set to anything.
+pub fn enabled(term: Option<&str>, no_color: Option<&str>) -> bool {
+    term != Some("dumb") && no_color.map_or(true, str::is_empty)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Plain,
+    Command,
+    Error,
+    String,
+    Variable,
+    Operator,
+    Redirect,
+    Comment,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Style {
+    kind: Kind,
+    underline: bool,
+    matching: bool,
+}
+
+struct Scanner<'a> {
+    chars: Vec<char>,
+    styles: Vec<Style>,
+    // Brackets and quotes that belong together, as char indices.
+    pairs: Vec<(usize, usize)>,
+    // Open brackets waiting for their partner.
+    open: Vec<(usize, char)>,
+    resolves: &'a dyn Fn(&str) -> bool,
+}
+
+// Returns `line` with colours added. `pos` is the cursor's byte offset;
+// `resolves` tells whether a command name can be run.
+pub fn highlight(line: &str, pos: usize, palette: &Palette, resolves: &dyn Fn(&str) -> bool) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let plain = Style { kind: Kind::Plain, underline: false, matching: false };
+    let mut scanner = Scanner { styles: vec![plain; chars.len()], chars, pairs: Vec::new(), open: Vec::new(), resolves };
+    scanner.scan();
+
+    let cursor = line[..pos].chars().count();
+    let partner = |index: usize| {
+        scanner.pairs.iter().find_map(|&(open, close)| {
+            if index == open {
+                Some(close)
+            } else if index == close {
+                Some(open)
+            } else {
+                None
+            }
+        })
+    };
+    // The bracket under the cursor, or else the one just before it.
+    let matched = partner(cursor)
+        .map(|other| (cursor, other))
+        .or_else(|| cursor.checked_sub(1).and_then(|before| partner(before).map(|other| (before, other))));
+    if let Some((a, b)) = matched {
+        scanner.styles[a].matching = true;
+        scanner.styles[b].matching = true;
+    }
+
+    let mut out = String::new();
+    let mut current = String::new();
+    for (c, style) in scanner.chars.iter().zip(&scanner.styles) {
+        let mut params: Vec<&str> = Vec::new();
+        params.push(palette.color(style.kind));
+        if style.underline {
+            params.push(&palette.path);
+        }
+        if style.matching {
+            params.push(&palette.matching);
+        }
+        params.retain(|param| !param.is_empty());
+        let sgr = params.join(";");
+        if sgr != current {
+            if !current.is_empty() {
+                out.push_str("\x1b[0m");
+            }
+            if !sgr.is_empty() {
+                out.push_str(&format!("\x1b[{}m", sgr));
+            }
+            current = sgr;
+        }
+        out.push(*c);
+    }
+    if !current.is_empty() {
+        out.push_str("\x1b[0m");
+    }
+    out
+}
+
+impl Scanner<'_> {
+    fn scan(&mut self) {
+        let mut i = 0;
+        // Whether the next word names a command.
+        let mut command_position = true;
+        // Whether the next word is the target of a redirection.
+        let mut redirect_target = false;
+        // Inside `[[ ... ]]`, where words are operands.
+        let mut in_test = false;
+
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            match c {
+                ' ' | '\t' => i += 1,
+                '\\' if self.chars.get(i + 1) == Some(&'\n') => i += 2,
+                '#' => {
+                    let end = self.chars[i..].iter().position(|&c| c == '\n').map_or(self.chars.len(), |n| i + n);
+                    self.paint(i, end, Kind::Comment);
+                    i = end;
+                }
+                '|' | '&' | ';' | '(' | ')' | '\n' => {
+                    let mut end = i + 1;
+                    if matches!(c, '|' | '&') && self.chars.get(end) == Some(&c) {
+                        end += 1;
+                    }
+                    self.paint(i, end, Kind::Operator);
+                    match c {
+                        '(' => self.open.push((i, '(')),
+                        ')' => self.close(i, '('),
+                        _ => {}
+                    }
+                    // After `)` only `}` or another operator may follow.
+                    command_position = true;
+                    i = end;
+                }
+                '<' | '>' => {
+                    i = self.redirect(i, i);
+                    redirect_target = true;
+                }
+                _ => {
+                    let start = i;
+                    let end = self.word(i);
+                    let word: String = self.chars[start..end].iter().collect();
+                    i = end;
+                    // Digits right before `<` or `>` are the redirection's fd.
+                    if word.chars().all(|d| d.is_ascii_digit()) && matches!(self.chars.get(end), Some('<' | '>')) {
+                        i = self.redirect(start, end);
+                        redirect_target = true;
+                        continue;
+                    }
+                    if redirect_target {
+                        redirect_target = false;
+                        self.underline_path(start, end, &word);
+                    } else if in_test {
+                        if word == "]]" {
+                            self.reserved(start, end, &word);
+                            in_test = false;
+                            command_position = false;
+                        } else {
+                            self.underline_path(start, end, &word);
+                        }
+                    } else if command_position {
+                        if crate::variables::parse_assignment(&word).is_some() {
+                            // An assignment before the command leaves the
+                            // next word in command position.
+                            continue;
+                        }
+                        if crate::parser::is_reserved_word(&word) {
+                            self.reserved(start, end, &word);
+                            in_test = word == "[[";
+                            command_position = matches!(word.as_str(), "{" | "}" | "!" | "function");
+                            continue;
+                        }
+                        command_position = false;
+                        self.command(start, end, &word);
+                    } else {
+                        self.underline_path(start, end, &word);
+                    }
+                }
+            }
+        }
+    }
+
+    fn paint(&mut self, start: usize, end: usize, kind: Kind) {
+        for style in &mut self.styles[start..end] {
+            style.kind = kind;
+        }
+    }
+
+    fn close(&mut self, index: usize, open: char) {
+        if self.open.last().is_some_and(|&(_, c)| c == open) {
+            let (start, _) = self.open.pop().unwrap();
+            self.pairs.push((start, index));
+        }
+    }
+
+    // Paints a redirection operator starting at `op` (after any fd digits
+    // from `start`), with a `&fd` that follows it. Returns where it ends.
+    fn redirect(&mut self, start: usize, op: usize) -> usize {
+        let mut end = op + 1;
+        if self.chars[op] == '>' && self.chars.get(end) == Some(&'>') {
+            end += 1;
+        }
+        if self.chars.get(end) == Some(&'&') {
+            end += 1;
+            while self.chars.get(end).is_some_and(|c| c.is_ascii_digit() || *c == '-') {
+                end += 1;
+            }
+        }
+        self.paint(start, end, Kind::Redirect);
+        end
+    }
+
+    fn reserved(&mut self, start: usize, end: usize, word: &str) {
+        self.paint(start, end, Kind::Command);
+        match word {
+            "{" => self.open.push((start, '{')),
+            "}" => self.close(start, '{'),
+            _ => {}
+        }
+    }
+
+    // Colours a command word by whether it resolves. A word that needs
+    // expanding cannot be looked up and keeps its inner colours.
+    fn command(&mut self, start: usize, end: usize, word: &str) {
+        let Some(name) = unquote(word) else {
+            return;
+        };
+        // `name()` defines a function rather than running a command.
+        let defines = self.chars[end..].iter().find(|c| !matches!(c, ' ' | '\t')) == Some(&'(');
+        let kind = if defines || (self.resolves)(&name) { Kind::Command } else { Kind::Error };
+        self.paint(start, end, kind);
+        if name.contains('/') && Path::new(&name).exists() {
+            self.underline(start, end);
+        }
+    }
+
+    fn underline_path(&mut self, start: usize, end: usize, word: &str) {
+        if unquote(word).is_some_and(|path| !path.is_empty() && Path::new(&path).exists()) {
+            self.underline(start, end);
+        }
+    }
+
+    fn underline(&mut self, start: usize, end: usize) {
+        for style in &mut self.styles[start..end] {
+            style.underline = true;
+        }
+    }
+
+    // Scans a word starting at `start`, painting its strings and variables.
+    // Returns the index just past it.
+    fn word(&mut self, start: usize) -> usize {
+        let mut i = start;
+        while i < self.chars.len() {
+            match self.chars[i] {
+                ' ' | '\t' | '|' | '&' | ';' | ')' | '\n' | '<' | '>' => break,
+                '(' => {
+                    let prefix: String = self.chars[start..i].iter().collect();
+                    if !crate::lexer::is_assignment_prefix(&prefix) {
+                        break;
+                    }
+                    // A compound array assignment runs to its `)`.
+                    self.open.push((i, '('));
+                    i += 1;
+                    while i < self.chars.len() && self.chars[i] != ')' {
+                        i = self.word_char(i);
+                    }
+                    if i < self.chars.len() {
+                        self.close(i, '(');
+                        i += 1;
+                    }
+                }
+                _ => i = self.word_char(i),
+            }
+        }
+        i
+    }
+
+    // Scans one piece of a word: a character, an escape, a quoted string or
+    // an expansion. Returns the index after it.
+    fn word_char(&mut self, i: usize) -> usize {
+        match self.chars[i] {
+            '\\' => (i + 2).min(self.chars.len()),
+            quote @ ('\'' | '"') => {
+                let mut j = i + 1;
+                while j < self.chars.len() && self.chars[j] != quote {
+                    if quote == '"' && self.chars[j] == '\\' {
+                        j += 2;
+                    } else if quote == '"' && self.chars[j] == '$' {
+                        self.paint(i, j, Kind::String);
+                        let end = self.variable(j);
+                        return self.resume_string(i, end);
+                    } else {
+                        j += 1;
+                    }
+                }
+                let end = (j + 1).min(self.chars.len());
+                self.paint(i, end, Kind::String);
+                if j < self.chars.len() {
+                    self.pairs.push((i, j));
+                }
+                end
+            }
+            '$' => self.variable(i),
+            _ => i + 1,
+        }
+    }
+
+    // Continues a double-quoted string opened at `open` after an expansion
+    // inside it ended at `from`.
+    fn resume_string(&mut self, open: usize, from: usize) -> usize {
+        let mut j = from;
+        let mut run = from;
+        while j < self.chars.len() && self.chars[j] != '"' {
+            if self.chars[j] == '\\' {
+                j += 2;
+            } else if self.chars[j] == '$' {
+                self.paint(run, j, Kind::String);
+                j = self.variable(j);
+                run = j;
+            } else {
+                j += 1;
+            }
+        }
+        let j = j.min(self.chars.len());
+        let end = (j + 1).min(self.chars.len());
+        self.paint(run, end, Kind::String);
+        if j < self.chars.len() {
+            self.pairs.push((open, j));
+        }
+        end
+    }
+
+    // Paints the expansion starting with the `$` at `i`. Returns the index
+    // after it; a `$` that starts no expansion is left alone.
+    fn variable(&mut self, i: usize) -> usize {
+        let end = match self.chars.get(i + 1) {
+            Some('{') => {
+                let mut depth = 0;
+                let mut j = i + 1;
+                let mut closed = None;
+                while j < self.chars.len() {
+                    match self.chars[j] {
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                closed = Some(j);
+                                break;
+                            }
+                        }
+                        '\\' => j += 1,
+                        _ => {}
+                    }
+                    j += 1;
+                }
+                if let Some(close) = closed {
+                    self.pairs.push((i + 1, close));
+                }
+                closed.map_or(self.chars.len(), |close| close + 1)
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let mut j = i + 1;
+                while self.chars.get(j).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    j += 1;
+                }
+                j
+            }
+            Some(c) if c.is_ascii_digit() || matches!(c, '?' | '#' | '@' | '*' | '$' | '!' | '-') => i + 2,
+            _ => return i + 1,
+        };
+        self.paint(i, end, Kind::Variable);
+        end
+    }
+}
+
+// The word with its quotes and escapes removed and a leading `~` expanded,
+// or None if it holds an expansion whose value cannot be known here.
+fn unquote(word: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = word.chars();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => out.push(c),
+            (_, '$') => return None,
+            (None, '\'' | '"') => quote = Some(c),
+            (_, '\\') => out.extend(chars.next()),
+            (_, c) => out.push(c),
+        }
+    }
+    if word.starts_with('~') && (out == "~" || out.starts_with("~/")) {
+        let home = std::env::var("HOME").ok()?;
+        out.replace_range(..1, &home);
+    }
+    Some(out)
+}
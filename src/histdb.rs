@@ -0,0 +1,285 @@
+// A structured record of every interactive command: when and where it
+// ran, how long it took and how it exited. Records are kept one JSON
+// object per line so that concurrent shells can append to the same file.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+pub struct Record {
+    // Seconds since the epoch.
+    pub start: i64,
+    pub duration_ms: u64,
+    pub cwd: String,
+    pub status: i32,
+    pub host: String,
+    pub session: String,
+    pub command: String,
+}
+
+// The store file: $HISTDB, or a file under the XDG data directory. An
+// empty HISTDB turns recording off.
+pub fn path(histdb: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = histdb {
+        return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+    let data_home = std::env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".local/share")))?;
+    Some(data_home.join("codecrafters-shell").join("history.jsonl"))
+}
+
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return String::new();
+    }
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// An id that tells this shell's records apart from other sessions'.
+pub fn session_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    format!("{:x}-{:x}", std::process::id(), nanos)
+}
+
+// Appends a record, under the same lock as a history file, so that
+// records from concurrent shells do not interleave.
+pub fn append(path: &std::path::Path, record: &Record) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let _lock = crate::histfile::lock(path)?;
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", to_json(record)).as_bytes())
+}
+
+// Reads every record in the store, skipping lines that cannot be parsed.
+pub fn load(path: &std::path::Path) -> std::io::Result<Vec<Record>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents.lines().filter_map(parse_record).collect())
+}
+
+pub fn to_json(record: &Record) -> String {
+    format!(
+        "{{\"start\":{},\"duration_ms\":{},\"cwd\":{},\"status\":{},\"host\":{},\"session\":{},\"command\":{}}}",
+        record.start,
+        record.duration_ms,
+        json_string(&record.cwd),
+        record.status,
+        json_string(&record.host),
+        json_string(&record.session),
+        json_string(&record.command),
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+enum JsonValue {
+    String(String),
+    Number(i64),
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut fields = parse_object(line)?;
+    let mut string = |name: &str| match fields.remove(name) {
+        Some(JsonValue::String(text)) => Some(text),
+        _ => None,
+    };
+    let (cwd, host, session, command) = (string("cwd")?, string("host")?, string("session")?, string("command")?);
+    let number = |name: &str| match fields.get(name) {
+        Some(JsonValue::Number(number)) => Some(*number),
+        _ => None,
+    };
+    Some(Record {
+        start: number("start")?,
+        duration_ms: number("duration_ms")?.try_into().ok()?,
+        cwd,
+        status: number("status")?.try_into().ok()?,
+        host,
+        session,
+        command,
+    })
+}
+
+// Parses a flat JSON object whose values are strings or integers, which
+// is all the store writes.
+fn parse_object(text: &str) -> Option<HashMap<String, JsonValue>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    let skip_blanks = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_blanks(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Some(fields);
+    }
+    loop {
+        skip_blanks(&mut chars);
+        let key = parse_string(&mut chars)?;
+        skip_blanks(&mut chars);
+        chars.next_if_eq(&':')?;
+        skip_blanks(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            JsonValue::String(parse_string(&mut chars)?)
+        } else {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '-') {
+                number.push(c);
+            }
+            JsonValue::Number(number.parse().ok()?)
+        };
+        fields.insert(key, value);
+        skip_blanks(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            // Nothing may follow the object, or the line is not one record.
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => match chars.next()? {
+                'n' => text.push('\n'),
+                'r' => text.push('\r'),
+                't' => text.push('\t'),
+                'b' => text.push('\u{8}'),
+                'f' => text.push('\u{c}'),
+                'u' => {
+                    let code: String = (0..4).map_while(|_| chars.next_if(char::is_ascii_hexdigit)).collect();
+                    if code.len() != 4 {
+                        return None;
+                    }
+                    text.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => text.push(c),
+            },
+            c => text.push(c),
+        }
+    }
+}
+
+// Parses a time for a query: seconds since the epoch, or a span back from
+// `now` such as `30m`, `2h` or `7d`.
+pub fn parse_time(text: &str, now: i64) -> Option<i64> {
+    if let Ok(time) = text.parse() {
+        return Some(time);
+    }
+    let unit = text.chars().last()?;
+    let count: i64 = text[..text.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(now - count * seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(command: &str, cwd: &str) -> Record {
+        Record {
+            start: 1697040000,
+            duration_ms: 1500,
+            cwd: cwd.to_string(),
+            status: -1,
+            host: "host".to_string(),
+            session: "1f-2e".to_string(),
+            command: command.to_string(),
+        }
+    }
+
+    fn fields(record: &Record) -> (i64, u64, &str, i32, &str, &str, &str) {
+        (
+            record.start,
+            record.duration_ms,
+            &record.cwd,
+            record.status,
+            &record.host,
+            &record.session,
+            &record.command,
+        )
+    }
+
+    #[test]
+    fn records_round_trip_through_json() {
+        let commands = [
+            "echo \"quoted\" 'single'",
+            "printf 'a\\tb\\n' C:\\dir\\",
+            "line one\nline two\r\tend",
+            "bell \u{7} escape \u{1b}[0m nul-ish \u{1}",
+            "echo héllo wörld ✓ 日本 🎉",
+        ];
+        for command in commands {
+            let written = record(command, "/tmp/dïr \"x\"");
+            let json = to_json(&written);
+            assert!(!json.contains('\n'), "{}", json);
+            let read = parse_record(&json).expect(&json);
+            assert_eq!(fields(&read), fields(&written));
+        }
+    }
+
+    #[test]
+    fn unicode_escapes_are_read() {
+        let line = r#"{"start":1,"duration_ms":2,"cwd":"/\u00e9","status":0,"host":"h","session":"s","command":"\u0041\"\\"}"#;
+        let read = parse_record(line).unwrap();
+        assert_eq!(read.cwd, "/é");
+        assert_eq!(read.command, "A\"\\");
+    }
+
+    #[test]
+    fn broken_lines_are_skipped() {
+        let json = to_json(&record("ls", "/"));
+        assert!(parse_record(&json[..json.len() - 1]).is_none());
+        assert!(parse_record(&json[..json.len() / 2]).is_none());
+        assert!(parse_record(&format!("{}garbage", json)).is_none());
+        assert!(parse_record(&format!("{}{}", json, json)).is_none());
+        assert!(parse_record("").is_none());
+        assert!(parse_record("not json").is_none());
+        assert!(parse_record(r#"{"start":1}"#).is_none());
+        assert!(parse_record(&json.replace("\"ls\"", "\"\\u00\"")).is_none());
+        assert!(parse_record(&json.replace("1500", "\"1500\"")).is_none());
+    }
+}
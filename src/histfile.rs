@@ -0,0 +1,274 @@
+// History files: reading and writing the bash format with `#epoch`
+// timestamp lines, locking and appending for shells that share a file, the
+// per-project history files, and the temporary files that `fc` and
+// Ctrl-X Ctrl-E edit commands in.
+
+// A history entry as stored in a history file.
+pub struct FileEntry {
+    pub line: String,
+    pub time: i64,
+}
+
+// The current time in seconds since the epoch.
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Whether a history file line is a bash `#1697040000` timestamp.
+fn parse_timestamp(line: &str) -> Option<i64> {
+    let digits = line.strip_prefix('#')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Parses a history file in the bash format, where an entry may be preceded
+// by a `#epoch` line giving the time it was run. The line after a
+// timestamp is always a command, so a comment command that looks like a
+// timestamp still comes back intact. As in bash, a file that starts with a
+// timestamp was written in that format throughout, so the lines up to the
+// next timestamp belong to the same multi-line command; in any other file
+// each plain line is an entry of its own. Entries without a timestamp get
+// `default_time`.
+pub fn parse_file(contents: &str, default_time: i64) -> Vec<FileEntry> {
+    let multiline = contents.lines().next().is_some_and(|line| parse_timestamp(line).is_some());
+    let mut entries: Vec<FileEntry> = Vec::new();
+    let mut time = None;
+    // Whether the last entry had a timestamp and may take more lines.
+    let mut stamped = false;
+    for line in contents.lines() {
+        if time.is_none() {
+            if let Some(stamp) = parse_timestamp(line) {
+                time = Some(stamp);
+                stamped = false;
+                continue;
+            }
+        }
+        match (time.take(), entries.last_mut()) {
+            (Some(time), _) => {
+                entries.push(FileEntry { line: line.to_string(), time });
+                stamped = multiline;
+            }
+            (None, Some(entry)) if stamped => {
+                entry.line.push('\n');
+                entry.line.push_str(line);
+            }
+            (None, _) if !line.is_empty() => {
+                entries.push(FileEntry { line: line.to_string(), time: default_time });
+            }
+            (None, _) => {}
+        }
+    }
+    entries
+}
+
+// Formats an entry for a history file, with its timestamp line.
+pub fn format_entry(line: &str, time: i64) -> String {
+    format!("#{}\n{}\n", time, line)
+}
+
+// Formats a time with a strftime format, as `history` does for
+// HISTTIMEFORMAT.
+pub fn format_time(format: &str, time: i64) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let mut buffer = vec![0u8; 256];
+    let len = unsafe { libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), format.as_ptr(), &tm) };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// Takes an exclusive lock shared by all shells writing a history file,
+// held until the returned file is dropped. The lock is on a `.lock` file
+// beside it, since the history file itself is replaced by rename.
+pub fn lock(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    use std::os::fd::AsRawFd;
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+// Replaces a file's contents by writing a temporary file and renaming it
+// over the original, so readers never see it half written. A symlink is
+// followed, so that the file it points to is replaced rather than the
+// link, and the file keeps its permissions; a new file is made private.
+pub fn write_atomic(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    let target = match std::fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(e),
+    };
+    let mode = std::fs::metadata(&target).map_or(0o600, |metadata| metadata.permissions().mode() & 0o7777);
+    // A fresh name made with mkstemps, so that nothing planted beside the
+    // target in advance can be written through.
+    let mut template = target.as_os_str().to_owned();
+    template.push(".XXXXXX");
+    let (temp_path, mut file) = create_unique(template.into(), "")?;
+    // mkstemps creates it private; give it the mode of the file it replaces.
+    let written = file
+        .set_permissions(std::fs::Permissions::from_mode(mode))
+        .and_then(|()| file.write_all(contents.as_bytes()));
+    written.and_then(|()| std::fs::rename(&temp_path, &target)).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_path);
+    })
+}
+
+// The part of a history file not read yet.
+pub struct Tail {
+    // The file's inode and length when it was read.
+    pub inode: u64,
+    pub len: u64,
+    // Whether the whole file was read, because it was never read before or
+    // has been replaced or cut short since.
+    pub restarted: bool,
+    pub text: String,
+}
+
+// Reads a history file from byte `offset` on, if `seen` gives the inode it
+// had and that offset. Otherwise the whole file is read.
+pub fn read_tail(path: &std::path::Path, seen: Option<(u64, u64)>) -> std::io::Result<Tail> {
+    use std::io::{Read, Seek};
+    use std::os::unix::fs::MetadataExt;
+    let mut file = std::fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let (inode, len) = (metadata.ino(), metadata.len());
+    let start = match seen {
+        Some((seen_inode, offset)) if seen_inode == inode && offset <= len => offset,
+        _ => 0,
+    };
+    file.seek(std::io::SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.take(len - start).read_to_end(&mut bytes)?;
+    let text = String::from_utf8_lossy(&bytes).into_owned();
+    Ok(Tail { inode, len, restarted: start == 0, text })
+}
+
+// Adds entries to the end of a history file, creating it private if it
+// does not exist. The caller holds the lock.
+pub fn append_entries(path: &std::path::Path, entries: &[FileEntry]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let contents: String = entries.iter().map(|entry| format_entry(&entry.line, entry.time)).collect();
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+// Creates a file for a command to be edited in, in the temporary
+// directory under a fresh random name ending in `suffix`. The file is
+// created exclusively, so a name or symlink planted there in advance
+// cannot redirect the write.
+pub fn temp_file(prefix: &str, suffix: &str) -> std::io::Result<(std::path::PathBuf, std::fs::File)> {
+    create_unique(std::env::temp_dir().join(format!("{}.XXXXXX{}", prefix, suffix)), suffix)
+}
+
+// Creates a file exclusively with mkstemps, which fills in the `XXXXXX`
+// of `template` that comes right before `suffix`.
+fn create_unique(template: std::path::PathBuf, suffix: &str) -> std::io::Result<(std::path::PathBuf, std::fs::File)> {
+    use std::os::fd::FromRawFd;
+    use std::os::unix::ffi::OsStringExt;
+    let mut bytes = template.into_os_string().into_vec();
+    bytes.push(0);
+    let fd = unsafe { libc::mkstemps(bytes.as_mut_ptr().cast(), suffix.len() as libc::c_int) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    bytes.pop();
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    Ok((std::ffi::OsString::from_vec(bytes).into(), file))
+}
+
+// Where the history of the project rooted at `root` is kept: a file named
+// after the root's path in $HISTPROJECTDIR, or in a directory under the
+// XDG data directory.
+pub fn project_file(dir: Option<&str>, root: &std::path::Path) -> Option<std::path::PathBuf> {
+    let dir = match dir.filter(|dir| !dir.is_empty()) {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::env::var("XDG_DATA_HOME")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(std::path::PathBuf::from)
+            .or_else(|| std::env::var("HOME").ok().map(|home| std::path::Path::new(&home).join(".local/share")))?
+            .join("codecrafters-shell/projects"),
+    };
+    let name = root.to_string_lossy().replace('%', "%25").replace('/', "%2F");
+    Some(dir.join(name))
+}
+
+// Adds one entry to the end of a history file.
+pub fn append_entry(path: &std::path::Path, line: &str, time: i64) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let _lock = lock(path)?;
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format_entry(line, time).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(contents: &str) -> Vec<(String, i64)> {
+        parse_file(contents, 7).into_iter().map(|entry| (entry.line, entry.time)).collect()
+    }
+
+    #[test]
+    fn formatted_entries_parse_back() {
+        let written = [("ls -l", 100), ("for x in a b\ndo echo $x\ndone", 200), ("#123", 300)];
+        let contents: String = written.iter().map(|(line, time)| format_entry(line, *time)).collect();
+        let expected: Vec<(String, i64)> = written.iter().map(|(line, time)| (line.to_string(), *time)).collect();
+        assert_eq!(entries(&contents), expected);
+    }
+
+    #[test]
+    fn plain_lines_are_entries_of_their_own() {
+        assert_eq!(entries("ls\n\necho hi\n"), vec![("ls".to_string(), 7), ("echo hi".to_string(), 7)]);
+        // A file that does not start with a timestamp never joins lines.
+        assert_eq!(
+            entries("ls\n#100\necho a\necho b\n"),
+            vec![("ls".to_string(), 7), ("echo a".to_string(), 100), ("echo b".to_string(), 7)]
+        );
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file_and_keeps_its_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let (path, _) = temp_file("histfile-test", "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, "#1\nls\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "#1\nls\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let leftovers = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&format!("{}.", name)))
+            .count();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn only_digits_make_a_timestamp() {
+        assert_eq!(parse_timestamp("#1697040000"), Some(1697040000));
+        assert_eq!(parse_timestamp("#"), None);
+        assert_eq!(parse_timestamp("# 12"), None);
+        assert_eq!(parse_timestamp("#12a"), None);
+    }
+}
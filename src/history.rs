@@ -0,0 +1,427 @@
+// csh-style history expansion of interactive lines: event designators
+// (`!!`, `!n`, `!-n`, `!prefix`, `!?text?`, `^old^new^`), word designators
+// and modifiers.
+
+// The result of expanding a line.
+pub struct Expansion {
+    pub line: String,
+    // Whether any history reference was replaced.
+    pub expanded: bool,
+    // A `:p` modifier asked for the line to be printed but not run.
+    pub print_only: bool,
+}
+
+// Characters that end a `!prefix` search string and split history words.
+const METACHARACTERS: &str = "|&;<>()";
+
+struct Expander<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    history: &'a [String],
+    // How many entries came before the first in `history`, for `!n`.
+    base: usize,
+    // The text of the last `!?text?` search, for the `%` word designator.
+    search: Option<String>,
+    // The last `:s` substitution, for `:&`.
+    substitution: Option<(String, String)>,
+    print_only: bool,
+}
+
+// Expands the history references in `line`. `history` is the history
+// list, the first entry of which is number `base + 1`.
+pub fn expand(line: &str, history: &[String], base: usize) -> Result<Expansion, String> {
+    // `^old^new^` is shorthand for `!!:s^old^new^`.
+    let line = if line.starts_with('^') { format!("!!:s{}", line) } else { line.to_string() };
+    if !line.contains('!') {
+        return Ok(Expansion { line, expanded: false, print_only: false });
+    }
+
+    let mut expander = Expander {
+        chars: line.chars().collect(),
+        pos: 0,
+        history,
+        base,
+        search: None,
+        substitution: None,
+        print_only: false,
+    };
+    let mut out = String::new();
+    let mut expanded = false;
+    let mut in_single = false;
+    let mut in_double = false;
+    while let Some(&c) = expander.chars.get(expander.pos) {
+        expander.pos += 1;
+        if in_single {
+            in_single = c != '\'';
+            out.push(c);
+            continue;
+        }
+        match c {
+            '\\' => {
+                out.push(c);
+                if let Some(&next) = expander.chars.get(expander.pos) {
+                    out.push(next);
+                    expander.pos += 1;
+                }
+            }
+            '\'' if !in_double => {
+                in_single = true;
+                out.push(c);
+            }
+            '"' => {
+                in_double = !in_double;
+                out.push(c);
+            }
+            '!' if expander.starts_reference(in_double) => {
+                let text = expander.reference(&out)?;
+                out.push_str(&text);
+                expanded = true;
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(Expansion { line: out, expanded, print_only: expander.print_only })
+}
+
+impl Expander<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn text(&self, start: usize) -> String {
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // Whether the `!` just consumed begins a history reference. It does not
+    // when followed by a blank, `=` or `(`, or when it is part of `$!` or
+    // `${!name}`.
+    fn starts_reference(&self, in_double: bool) -> bool {
+        let before = &self.chars[..self.pos - 1];
+        if before.ends_with(&['$']) || before.ends_with(&['$', '{']) {
+            return false;
+        }
+        match self.peek() {
+            None => false,
+            Some(c) => !(c.is_whitespace() || c == '=' || c == '(' || (in_double && c == '"')),
+        }
+    }
+
+    // Expands one reference, the `!` of which has been consumed. `current`
+    // is the line so far, for `!#`.
+    fn reference(&mut self, current: &str) -> Result<String, String> {
+        let start = self.pos - 1;
+        let event = self.event(current, start)?;
+        let words = split_words(&event);
+
+        let mut text = event.clone();
+        let designator_start = self.pos;
+        let explicit = self.peek() == Some(':')
+            && self.chars.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit() || "^$*%-".contains(*c));
+        if explicit {
+            self.pos += 1;
+        }
+        if explicit || self.peek().is_some_and(|c| "^$*%".contains(c)) {
+            text = match self.designator(&words) {
+                Some(text) => text,
+                None => {
+                    let spec = self.text(designator_start);
+                    return Err(format!("{}: bad word specifier", spec.trim_start_matches(':')));
+                }
+            };
+        }
+
+        while self.peek() == Some(':') {
+            let modifier_start = self.pos;
+            self.pos += 1;
+            if !self.modifier(&mut text, modifier_start)? {
+                self.pos = modifier_start;
+                break;
+            }
+        }
+        Ok(text)
+    }
+
+    // Reads an event designator and returns the history line it refers to.
+    fn event(&mut self, current: &str, start: usize) -> Result<String, String> {
+        let not_found = |spec: String| format!("{}: event not found", spec);
+        let previous = || self.history.last().cloned();
+        match self.peek() {
+            Some('!') => {
+                self.pos += 1;
+                previous().ok_or_else(|| not_found("!!".to_string()))
+            }
+            Some('#') => {
+                self.pos += 1;
+                Ok(current.to_string())
+            }
+            // `!$`, `!^`, `!*` and `!:n` refer to the previous line.
+            Some(':' | '^' | '$' | '*' | '%') => previous().ok_or_else(|| not_found(self.text(start))),
+            Some(c) if c.is_ascii_digit() || (c == '-' && self.chars.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit())) => {
+                self.pos += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                let number: i64 = self.text(start + 1).parse().unwrap_or(0);
+                let len = self.history.len() as i64;
+                let index = if number < 0 { len + number } else { number - 1 - self.base as i64 };
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|index| self.history.get(index).cloned())
+                    .ok_or_else(|| not_found(self.text(start)))
+            }
+            Some('?') => {
+                self.pos += 1;
+                let text_start = self.pos;
+                while self.peek().is_some_and(|c| c != '?') {
+                    self.pos += 1;
+                }
+                let search = self.text(text_start);
+                if self.peek() == Some('?') {
+                    self.pos += 1;
+                }
+                let found = self.history.iter().rev().find(|entry| entry.contains(&search)).cloned();
+                self.search = Some(search);
+                found.ok_or_else(|| not_found(self.text(start)))
+            }
+            _ => {
+                while self.peek().is_some_and(|c| !(c.is_whitespace() || c == ':' || c == '"' || c == '\'' || METACHARACTERS.contains(c))) {
+                    self.pos += 1;
+                }
+                let prefix = self.text(start + 1);
+                self.history
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.starts_with(&prefix))
+                    .cloned()
+                    .ok_or_else(|| not_found(self.text(start)))
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.text(start).parse().ok()
+    }
+
+    // Reads a word designator and returns the words it selects, or None if
+    // they are out of range.
+    fn designator(&mut self, words: &[String]) -> Option<String> {
+        let last = words.len().checked_sub(1)?;
+        let first = match self.peek()? {
+            '*' => {
+                self.pos += 1;
+                return Some(words.get(1..).map(|words| words.join(" ")).unwrap_or_default());
+            }
+            '^' => {
+                self.pos += 1;
+                1
+            }
+            '$' => {
+                self.pos += 1;
+                last
+            }
+            '%' => {
+                self.pos += 1;
+                let search = self.search.as_ref()?;
+                words.iter().position(|word| word.contains(search.as_str()))?
+            }
+            '-' => 0,
+            _ => self.number()?,
+        };
+        let end = match self.peek() {
+            Some('*') => {
+                self.pos += 1;
+                if first == last + 1 {
+                    return Some(String::new());
+                }
+                last
+            }
+            Some('-') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some('$') => {
+                        self.pos += 1;
+                        last
+                    }
+                    Some(c) if c.is_ascii_digit() => self.number()?,
+                    _ => last.checked_sub(1)?,
+                }
+            }
+            _ => first,
+        };
+        if first > end || end > last {
+            return None;
+        }
+        Some(words[first..=end].join(" "))
+    }
+
+    // Applies the modifier after a `:`. Returns false if the text there is
+    // not a modifier, leaving it in the line.
+    fn modifier(&mut self, text: &mut String, start: usize) -> Result<bool, String> {
+        let Some(c) = self.peek() else {
+            return Ok(false);
+        };
+        self.pos += 1;
+        match c {
+            'h' => {
+                if let Some(slash) = text.rfind('/') {
+                    text.truncate(slash);
+                }
+            }
+            't' => {
+                if let Some(slash) = text.rfind('/') {
+                    *text = text[slash + 1..].to_string();
+                }
+            }
+            'r' => {
+                if let Some(dot) = suffix_start(text) {
+                    text.truncate(dot);
+                }
+            }
+            'e' => {
+                if let Some(dot) = suffix_start(text) {
+                    *text = text[dot..].to_string();
+                }
+            }
+            'p' => self.print_only = true,
+            'q' => *text = quote(text),
+            'x' => *text = text.split_whitespace().map(quote).collect::<Vec<_>>().join(" "),
+            's' | '&' => self.substitute(text, c, false, start)?,
+            'g' | 'a' => match self.peek() {
+                Some(c @ ('s' | '&')) => {
+                    self.pos += 1;
+                    self.substitute(text, c, true, start)?;
+                }
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // `:s/old/new/` (any delimiter) or `:&`, the `s` or `&` of which has
+    // been consumed.
+    fn substitute(&mut self, text: &mut String, kind: char, global: bool, start: usize) -> Result<(), String> {
+        if kind == 's' {
+            let Some(delimiter) = self.peek() else {
+                return Err(format!("{}: bad substitution", self.text(start)));
+            };
+            self.pos += 1;
+            let old = self.delimited(delimiter);
+            let new = self.delimited(delimiter);
+            let old = match (old.is_empty(), &self.substitution) {
+                (true, Some((previous, _))) => previous.clone(),
+                _ => old,
+            };
+            let new = expand_ampersand(&new, &old);
+            self.substitution = Some((old, new));
+        }
+        let failed = || format!("{}: substitution failed", self.text(start));
+        let Some((old, new)) = &self.substitution else {
+            return Err(failed());
+        };
+        if old.is_empty() || !text.contains(old.as_str()) {
+            return Err(failed());
+        }
+        *text = if global { text.replace(old.as_str(), new) } else { text.replacen(old.as_str(), new, 1) };
+        Ok(())
+    }
+
+    // Reads up to an unescaped delimiter or the end of the line. A
+    // backslash escapes the delimiter; `\&` is kept for expand_ampersand.
+    fn delimited(&mut self, delimiter: char) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            if c == delimiter {
+                break;
+            }
+            if c == '\\' && self.peek() == Some(delimiter) {
+                text.push(delimiter);
+                self.pos += 1;
+            } else {
+                text.push(c);
+            }
+        }
+        text
+    }
+}
+
+// Replaces `&` in the replacement text of `:s` with the old text.
+fn expand_ampersand(new: &str, old: &str) -> String {
+    let mut out = String::new();
+    let mut chars = new.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('&') => out.push('&'),
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            '&' => out.push_str(old),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// The position of the `.` starting the suffix of the last path component.
+fn suffix_start(text: &str) -> Option<usize> {
+    let dot = text.rfind('.')?;
+    match text.rfind('/') {
+        Some(slash) if slash > dot => None,
+        _ => Some(dot),
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// Splits a history line into words for word designators: blank-separated,
+// keeping quoted text together, with runs of metacharacters as their own
+// words.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if METACHARACTERS.contains(c) {
+            while let Some(c) = chars.next_if(|c| METACHARACTERS.contains(*c)) {
+                word.push(c);
+            }
+            words.push(word);
+            continue;
+        }
+        let mut quote = None;
+        while let Some(&c) = chars.peek() {
+            if quote.is_none() && (c.is_whitespace() || METACHARACTERS.contains(c)) {
+                break;
+            }
+            chars.next();
+            word.push(c);
+            match (quote, c) {
+                (None, '\'' | '"') => quote = Some(c),
+                (Some(q), _) if q == c => quote = None,
+                (q, '\\') if q != Some('\'') => {
+                    if let Some(next) = chars.next() {
+                        word.push(next);
+                    }
+                }
+                _ => {}
+            }
+        }
+        words.push(word);
+    }
+    words
+}
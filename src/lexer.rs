@@ -0,0 +1,331 @@
+// Splits a command line into words and operators. Words are returned raw,
+// with their quotes and `$` expansions intact, so that expansion can tell
+// quoted text from unquoted text later on.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(String),
+    Operator(String),
+}
+
+// A word being read: its raw text, and its value with quotes and escapes
+// removed, which completion matches against. Expansions are kept as they
+// are in the value.
+#[derive(Default)]
+struct Word {
+    raw: String,
+    value: String,
+}
+
+impl Word {
+    fn push(&mut self, c: char) {
+        self.raw.push(c);
+        self.value.push(c);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    fn take(&mut self) -> String {
+        self.value.clear();
+        std::mem::take(&mut self.raw)
+    }
+}
+
+// A quote or bracket still open at the end of the input.
+struct Unclosed(char);
+
+impl Unclosed {
+    fn message(&self) -> String {
+        format!("unexpected EOF while looking for matching `{}'", self.0)
+    }
+}
+
+// What is left when the input runs out: the tokens so far, the word being
+// read and where it started (as a char index), and what was left open.
+struct Lexed {
+    tokens: Vec<Token>,
+    word: Word,
+    word_start: usize,
+    unclosed: Option<Unclosed>,
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut lexed = lex(line);
+    if let Some(unclosed) = lexed.unclosed {
+        return Err(unclosed.message());
+    }
+    if !lexed.word.is_empty() {
+        lexed.tokens.push(Token::Word(lexed.word.take()));
+    }
+    Ok(lexed.tokens)
+}
+
+// The word the cursor is in, for completion: where it starts, its value
+// with quotes and escapes removed, and the quote still open at the cursor.
+pub struct PartialWord {
+    pub start: usize,
+    pub value: String,
+    pub quote: Option<char>,
+}
+
+// Finds the last word of `line`, the text before the cursor, by the same
+// rules as tokenize. An unclosed quote is not an error here.
+pub fn partial_word(line: &str) -> PartialWord {
+    let lexed = lex(line);
+    let start = line.char_indices().nth(lexed.word_start).map_or(line.len(), |(i, _)| i);
+    let quote = lexed.unclosed.map(|unclosed| unclosed.0).filter(|c| matches!(c, '\'' | '"'));
+    PartialWord { start, value: lexed.word.value, quote }
+}
+
+fn lex(line: &str) -> Lexed {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut word = Word::default();
+    let mut word_start = 0;
+    let mut i = 0;
+    // Set after a `=~` word: the next word is a regular expression, in which
+    // `(`, `)` and `|` are ordinary characters.
+    let mut regex_operand = false;
+
+    let unclosed = loop {
+        if i >= chars.len() {
+            break None;
+        }
+        if word.is_empty() {
+            word_start = i;
+        }
+        let c = chars[i];
+        if regex_operand && !matches!(c, ' ' | '\t') {
+            regex_operand = false;
+            if c != '\n' {
+                match read_regex(&chars, i, &mut word) {
+                    Ok(end) => i = end,
+                    Err(unclosed) => break Some(unclosed),
+                }
+                continue;
+            }
+        }
+        let read = match c {
+            ' ' | '\t' => {
+                if !word.is_empty() {
+                    regex_operand = word.raw == "=~";
+                    tokens.push(Token::Word(word.take()));
+                }
+                Ok(i + 1)
+            }
+            '#' if word.is_empty() => {
+                // A comment runs to the end of the line.
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                Ok(i)
+            }
+            '\\' if chars.get(i + 1) == Some(&'\n') => {
+                // Line continuation.
+                Ok(i + 2)
+            }
+            '\\' => {
+                word.raw.push(c);
+                if let Some(&next) = chars.get(i + 1) {
+                    word.push(next);
+                }
+                Ok(i + 2)
+            }
+            '\'' | '"' => read_quoted(&chars, i, &mut word),
+            '$' if chars.get(i + 1) == Some(&'{') => read_braced(&chars, i, &mut word),
+            '(' if is_assignment_prefix(&word.raw) => read_compound(&chars, i, &mut word),
+            '|' | '&' | ';' | '(' | ')' | '\n' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(word.take()));
+                }
+                let mut op = c.to_string();
+                i += 1;
+                if matches!(c, '|' | '&') && chars.get(i) == Some(&c) {
+                    op.push(c);
+                    i += 1;
+                }
+                tokens.push(Token::Operator(op));
+                Ok(i)
+            }
+            '<' | '>' => {
+                // A word made only of digits right before the operator is its fd.
+                let mut op = String::new();
+                if !word.is_empty() && word.raw.chars().all(|d| d.is_ascii_digit()) {
+                    op = word.take();
+                } else if !word.is_empty() {
+                    tokens.push(Token::Word(word.take()));
+                }
+                op.push(c);
+                i += 1;
+                if chars.get(i) == Some(&c) {
+                    op.push(c);
+                    i += 1;
+                    // `<<-` is the here-document that strips leading tabs.
+                    if c == '<' && chars.get(i) == Some(&'-') {
+                        op.push('-');
+                        i += 1;
+                    }
+                }
+                tokens.push(Token::Operator(op));
+                Ok(i)
+            }
+            _ => {
+                word.push(c);
+                Ok(i + 1)
+            }
+        };
+        match read {
+            Ok(end) => i = end,
+            Err(unclosed) => break Some(unclosed),
+        }
+    };
+    if word.is_empty() {
+        word_start = chars.len();
+    }
+    Lexed { tokens, word, word_start, unclosed }
+}
+
+// True for `name=`, `name+=` and `name[sub]=`, which may be followed by
+// a parenthesised array value.
+pub fn is_assignment_prefix(word: &str) -> bool {
+    crate::variables::parse_assignment(word).is_some_and(|a| a.value.is_empty())
+}
+
+// Copies the `(...)` of a compound array assignment, blanks and quotes included.
+fn read_compound(chars: &[char], start: usize, word: &mut Word) -> Result<usize, Unclosed> {
+    word.push('(');
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            ')' => {
+                word.push(')');
+                return Ok(i + 1);
+            }
+            '\\' => {
+                word.push('\\');
+                if let Some(&next) = chars.get(i + 1) {
+                    word.push(next);
+                }
+                i += 2;
+            }
+            '\'' | '"' => {
+                i = read_quoted(chars, i, word)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                i = read_braced(chars, i, word)?;
+            }
+            c => {
+                word.push(c);
+                i += 1;
+            }
+        }
+    }
+    Err(Unclosed(')'))
+}
+
+// Copies the regular expression after `=~`, up to the first blank outside
+// quotes and parentheses.
+fn read_regex(chars: &[char], start: usize, word: &mut Word) -> Result<usize, Unclosed> {
+    let mut depth = 0;
+    let mut i = start;
+
+    while i < chars.len() {
+        match chars[i] {
+            ' ' | '\t' | '\n' if depth == 0 => break,
+            '\\' => {
+                word.push('\\');
+                if let Some(&next) = chars.get(i + 1) {
+                    word.push(next);
+                }
+                i += 2;
+            }
+            '\'' | '"' => {
+                i = read_quoted(chars, i, word)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                i = read_braced(chars, i, word)?;
+            }
+            c => {
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth > 0 => depth -= 1,
+                    _ => {}
+                }
+                word.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(i)
+}
+
+// Copies a quoted section starting at `start` into `word`, quotes included
+// in its raw text and left out of its value. Returns the index just past
+// the closing quote.
+fn read_quoted(chars: &[char], start: usize, word: &mut Word) -> Result<usize, Unclosed> {
+    let quote = chars[start];
+    word.raw.push(quote);
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == quote {
+            word.raw.push(c);
+            return Ok(i + 1);
+        }
+        if quote == '"' && c == '\\' && i + 1 < chars.len() {
+            let next = chars[i + 1];
+            word.raw.push(c);
+            if !matches!(next, '$' | '`' | '"' | '\\' | '\n') {
+                word.value.push(c);
+            }
+            word.push(next);
+            i += 2;
+            continue;
+        }
+        if quote == '"' && c == '$' && chars.get(i + 1) == Some(&'{') {
+            i = read_braced(chars, i, word)?;
+            continue;
+        }
+        word.push(c);
+        i += 1;
+    }
+    Err(Unclosed(quote))
+}
+
+// Copies a `${...}` expansion, which may itself contain quotes and blanks.
+fn read_braced(chars: &[char], start: usize, word: &mut Word) -> Result<usize, Unclosed> {
+    word.raw.push_str("${");
+    word.value.push_str("${");
+    let mut i = start + 2;
+
+    while i < chars.len() {
+        match chars[i] {
+            '}' => {
+                word.push('}');
+                return Ok(i + 1);
+            }
+            '\\' => {
+                word.push('\\');
+                if let Some(&next) = chars.get(i + 1) {
+                    word.push(next);
+                }
+                i += 2;
+            }
+            '\'' | '"' => {
+                i = read_quoted(chars, i, word)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                i = read_braced(chars, i, word)?;
+            }
+            c => {
+                word.push(c);
+                i += 1;
+            }
+        }
+    }
+    Err(Unclosed('}'))
+}
@@ -1,9 +1,31 @@
+mod arith;
+mod builtins;
+mod complete;
+mod conditional;
+mod expand;
+mod glob;
+mod highlight;
+mod histdb;
+mod histfile;
+mod history;
+mod lexer;
+mod parser;
+mod printf;
+mod redact;
+mod variables;
+
 use std::io::{Write};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-
-use std::path::{PathBuf};
-use std::os::unix::fs::PermissionsExt;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
 use std::fs::OpenOptions;
 use rustyline::config::Configurer;
 use rustyline::config::{CompletionType, Config, BellStyle};
@@ -12,14 +34,133 @@ use rustyline::Editor;
 use rustyline::completion::{Completer, Pair as CompletionPair};
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
-use rustyline::validate::Validator;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Cmd, ConditionalEventHandler, Event, EventContext, EventHandler, KeyCode, KeyEvent, Modifiers, Movement, RepeatCount};
 use rustyline::{Result, Context, Helper};
 
-const BUILTINS: &[&str] = &["echo", "exit", "type", "pwd", "cd", "history"];
+use parser::{Command, Connector, Pipeline, Redirect};
+use variables::Variables;
+
+const BUILTINS: &[&str] = &[
+    "echo", "exit", "type", "pwd", "cd", "history", "declare", "unset", "shift", "set", "getopts", "return",
+    "test", "[", "read", "mapfile", "readarray", "printf",
+    "shopt", "pushd", "popd", "dirs", "command", "which", "hash", "histquery", "fc",
+];
+
+// Options that `shopt` can turn on and off.
+const SHOPT_OPTIONS: &[&str] = &["histverify", "inc_append_history", "project_history", "share_history", "xpg_echo"];
+
+// Builtins whose `name=value` arguments are assignments, expanded like the
+// right-hand side of a variable assignment rather than as ordinary words.
+const DECLARATION_BUILTINS: &[&str] = &["declare"];
+
+// How execution should continue after the current command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Normal,
+    // `return` was run; unwind to the function call.
+    Return,
+    // An expansion failed in a shell that is not interactive, which gives
+    // up on the rest of its commands.
+    Exit,
+}
+
+// A pipeline stage that is still running and has to be waited for.
+enum PipelineChild {
+    Process(std::process::Child),
+    Forked(libc::pid_t),
+}
 
 pub struct Shell{
     editor: Editor<ShellHelper>,
-    history_append_files: HashMap<PathBuf, usize>
+    // How far into the history each file has been appended to, counting
+    // every entry added this session, so that entries trimmed off the
+    // front of the list do not shift it.
+    history_append_files: HashMap<PathBuf, usize>,
+    // How many entries have been trimmed off the front of the history list.
+    history_dropped: usize,
+    // What has been read of each history file and is known to be in it,
+    // so that `history -n` and share_history read only what was added since.
+    history_files: HashMap<PathBuf, HistoryFile>,
+    // When each history entry was run, in seconds since the epoch. It runs
+    // parallel to the editor's history list.
+    history_times: Vec<i64>,
+    // Identifies this shell's commands in the structured history store.
+    session_id: String,
+    // The project history spliced into the history list, if any.
+    project: Option<ProjectHistory>,
+    // The redaction rules, with the HISTREDACT and HISTREDACT_DROP values
+    // they were compiled from.
+    redactor: RefCell<Option<RedactorRules>>,
+    // The in-memory history limit last taken from HISTSIZE.
+    history_size: usize,
+    // Where the line being run was stored in the history list, counting
+    // every entry added this session, or None if it was not stored.
+    current_entry: Option<usize>,
+    vars: Variables,
+    last_status: i32,
+    // $0 and the positional parameters $1, $2, ...
+    script_name: String,
+    positional: Vec<String>,
+    functions: HashMap<String, Rc<Command>>,
+    function_depth: usize,
+    flow: Flow,
+    // Character offset into the argument getopts is scanning, for grouped
+    // options like `-abc`, and the OPTIND value and argument it belongs to.
+    getopts_offset: usize,
+    getopts_optind: usize,
+    getopts_arg: String,
+    // False for scripts and forked children, which must not write the
+    // history file on exit.
+    interactive: bool,
+    pipeline_children: Vec<PipelineChild>,
+    // The `shopt` options that are turned on.
+    shopts: HashSet<String>,
+    // The directory stack below the current directory, most recent first.
+    dir_stack: Vec<String>,
+    // Where commands were found in PATH, as listed by `hash`.
+    hash_table: BTreeMap<String, HashEntry>,
+    // The PATH the hash table was filled under. Assigning PATH empties it.
+    hash_path: String,
+    // The editor Ctrl-X Ctrl-E opens, shared with its key binding and
+    // updated from the shell variables before each line is read.
+    line_editor: Arc<Mutex<String>>,
+}
+
+type RedactorRules = ((Vec<String>, Vec<String>), Rc<redact::Redactor>);
+
+struct ProjectHistory {
+    // The directory holding the project marker.
+    root: PathBuf,
+    // Where the project's commands are kept.
+    file: PathBuf,
+    // Where its entries are in the history list, counting every entry
+    // added this session.
+    entries: std::ops::Range<usize>,
+}
+
+// A history file as this shell last read or wrote it.
+struct HistoryFile {
+    // Its inode. A file with another one has been replaced since, and is
+    // read again from the start.
+    inode: u64,
+    // How many bytes of it have been read, and how many entries they hold.
+    offset: u64,
+    count: usize,
+    // The entries known to be in it, read or written by this shell, so
+    // that none is taken in or written out twice.
+    known: HashSet<(i64, String)>,
+}
+
+struct HashEntry {
+    path: PathBuf,
+    hits: usize,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shell {
@@ -30,26 +171,187 @@ impl Shell {
             .bell_style(BellStyle::Audible)
             .build();
         
-        let mut all_commands = get_executables_in_path();
-        for builtin in BUILTINS {
-            if !all_commands.contains(&builtin.to_string()) {
-                all_commands.push(builtin.to_string());
-            }
-        }
-        let helper = ShellHelper { all_commands };
+        let path_var = env::var("PATH").unwrap_or_default();
+        let helper = ShellHelper { all_commands: completion_commands(&path_var), ..Default::default() };
         
+        let line_editor = Arc::new(Mutex::new(String::new()));
         let mut rl = Editor::<ShellHelper>::with_config(config).unwrap();
         rl.set_helper(Some(helper));
+        rl.bind_sequence(
+            Event::KeySeq(vec![KeyEvent::ctrl('X'), KeyEvent::ctrl('E')]),
+            EventHandler::Conditional(Box::new(EditInEditor::new(line_editor.clone()))),
+        );
+        // Right arrow already takes the whole suggestion at the end of the line.
+        rl.bind_sequence(
+            KeyEvent(KeyCode::End, Modifiers::NONE),
+            EventHandler::Conditional(Box::new(AcceptHint { one_word: false })),
+        );
+        rl.bind_sequence(
+            KeyEvent::alt('f'),
+            EventHandler::Conditional(Box::new(AcceptHint { one_word: true })),
+        );
 
-        if let Some(path) = Self::default_history_path()
-        {
-            let _ = rl.load_history(&path);
+        // HISTCONTROL decides which lines are kept; see add_history.
+        rl.set_history_ignore_dups(false);
+        rl.set_history_ignore_space(false);
+        
+        let mut shell = Shell::with_editor(rl, Variables::from_env(), path_var);
+        shell.line_editor = line_editor;
+        // Keep the inherited $PWD only if it still names the current directory.
+        if let Ok(dir) = builtins::current_dir(&shell, false) {
+            shell.vars.set("PWD", &dir.to_string_lossy());
+            shell.vars.export("PWD");
+        }
+        shell
+    }
+
+    // A shell around `editor` with `vars` and nothing run yet.
+    fn with_editor(editor: Editor<ShellHelper>, vars: Variables, path_var: String) -> Self {
+        Shell {
+            editor,
+            history_append_files: HashMap::new(),
+            history_dropped: 0,
+            history_files: HashMap::new(),
+            history_times: Vec::new(),
+            session_id: histdb::session_id(),
+            project: None,
+            redactor: RefCell::new(None),
+            history_size: 0,
+            current_entry: None,
+            vars,
+            last_status: 0,
+            script_name: env::args().next().unwrap_or_default(),
+            positional: Vec::new(),
+            functions: HashMap::new(),
+            function_depth: 0,
+            flow: Flow::Normal,
+            getopts_offset: 0,
+            getopts_optind: 1,
+            getopts_arg: String::new(),
+            interactive: true,
+            pipeline_children: Vec::new(),
+            shopts: HashSet::new(),
+            dir_stack: Vec::new(),
+            hash_table: BTreeMap::new(),
+            hash_path: path_var,
+            line_editor: Arc::default(),
         }
+    }
 
-        rl.set_history_ignore_dups(true); 
-        rl.set_history_ignore_space(true);
-        
-        Shell { editor: rl, history_append_files: HashMap::new() }
+    // A non-interactive shell with no variables, no helper and no key
+    // bindings, for testing expansion and arithmetic.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        let mut shell = Shell::with_editor(Editor::new().unwrap(), Variables::default(), String::new());
+        shell.interactive = false;
+        shell.script_name = "sh".to_string();
+        shell
+    }
+
+    // Reads HISTFILE into the history list. Only an interactive shell
+    // keeps history, so scripts and `-c` commands never touch the file.
+    fn load_history(&mut self) {
+        self.apply_history_size();
+        if let Some(path) = Self::default_history_path() {
+            let _ = self.load_history_file(&path);
+            // The loaded entries are already in the file.
+            let len = self.editor.history().len();
+            self.history_append_files.insert(path, self.history_dropped + len);
+        }
+    }
+
+    // Gives suggestions the earlier sessions' runs from the structured
+    // store, to tell where commands worked. Only an interactive shell
+    // suggests anything, so scripts never read the store.
+    fn load_runs(&mut self) {
+        let Some(path) = histdb::path(self.vars.get("HISTDB")) else {
+            return;
+        };
+        let records = histdb::load(&path).unwrap_or_default();
+        if let Some(helper) = self.editor.helper_mut() {
+            for record in &records {
+                helper.record_run(&record.command, &record.cwd, record.status);
+            }
+        }
+    }
+
+    // Looks a program up through the hash table, searching PATH and
+    // remembering the result on a miss or if the cached file is gone.
+    fn find_command(&mut self, name: &str) -> Option<PathBuf> {
+        if name.contains('/') {
+            return find_executable_in_path(name, &self.hash_path);
+        }
+        self.check_hash_path();
+        if let Some(entry) = self.hash_table.get_mut(name) {
+            if is_executable(&entry.path) {
+                entry.hits += 1;
+                return Some(entry.path.clone());
+            }
+        }
+        let path = find_executable_in_path(name, &self.hash_path)?;
+        self.hash_table.insert(name.to_string(), HashEntry { path: path.clone(), hits: 1 });
+        Some(path)
+    }
+
+    // The hashed path of `name`, if the table is still valid for PATH.
+    fn hashed(&self, name: &str) -> Option<&PathBuf> {
+        if self.vars.get("PATH").unwrap_or_default() != self.hash_path {
+            return None;
+        }
+        self.hash_table.get(name).map(|entry| &entry.path)
+    }
+
+    // Empties the hash table if PATH has been assigned since it was filled.
+    fn check_hash_path(&mut self) {
+        let path = self.vars.get("PATH").unwrap_or_default();
+        if path != self.hash_path {
+            self.hash_path = path.to_string();
+            self.hash_table.clear();
+            self.hash_changed();
+        }
+    }
+
+    // Rebuilds the completion list from PATH and the hash table after the
+    // table was changed or PATH assigned, so that programs installed since
+    // startup and names added with `hash -p` complete too. Only an
+    // interactive shell completes, so scripts skip the scan.
+    fn hash_changed(&mut self) {
+        if !self.interactive {
+            return;
+        }
+        let mut all_commands = completion_commands(&self.hash_path);
+        for name in self.hash_table.keys() {
+            if !all_commands.contains(name) {
+                all_commands.push(name.clone());
+            }
+        }
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.all_commands = all_commands;
+        }
+    }
+
+    // Gives the line editor what colouring, suggestions and completion
+    // need from the shell: whether colour is wanted, the colours, the
+    // functions defined so far, the current directory and the variables.
+    fn update_helper(&mut self) {
+        let enabled = highlight::enabled(self.vars.get("TERM"), self.vars.get("NO_COLOR"));
+        let palette = enabled.then(|| highlight::Palette::new(self.vars.get("HIGHLIGHT_COLORS")));
+        let functions = self.functions.keys().cloned().collect();
+        let cwd = self.vars.get("PWD").unwrap_or_default().to_string();
+        *self.line_editor.lock().unwrap() = builtins::editor_command(&self.vars, "VISUAL");
+        let vars = self.vars.names().into_iter().filter_map(|name| Some((name.to_string(), self.vars.get(name)?.to_string()))).collect();
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.palette = palette;
+            helper.functions = functions;
+            helper.cwd = cwd;
+            helper.vars = vars;
+            // A new line sees files created by the last command.
+            helper.listing = RefCell::default();
+        }
+    }
+
+    fn shopt_enabled(&self, name: &str) -> bool {
+        self.shopts.contains(name)
     }
 
     fn default_history_path() -> Option<PathBuf> {
@@ -59,101 +361,503 @@ impl Shell {
             }
         }
 
-        return None
+        None
     }
 
-    fn save_history(&mut self, path: &PathBuf) -> Result<()> {
+    // Overwrites a history file with the history list, as `history -w` does.
+    fn save_history(&mut self, path: &Path) -> Result<()> {
+        let entries = self.global_entries(0);
+        let file = histfile::lock(path)
+            .and_then(|_lock| self.write_history_file(path, &entries))
+            .map_err(|e| {
+                eprintln!("Error writing to file {}: {}", path.display(), e);
+                ReadlineError::Io(e)
+            })?;
+        self.history_files.insert(path.to_owned(), file);
+        let end = self.history_dropped + self.editor.history().len();
+        self.history_append_files.insert(path.to_owned(), end);
+        Ok(())
+    }
 
-        let history = self.editor.history();
-        match OpenOptions::new().write(true).create(true).truncate(true).open(&path) {
-            Ok(mut file) => {
-                for entry in history {
-                    if entry.starts_with("#") {
-                        continue;
-                    }
-                    if let Err(e) = writeln!(file, "{}", entry) {
-                        eprintln!("Error writing to file {}: {}", path.display(), e);
-                    }
-                }
-                Ok(())
-            }
+    // The history entries from index `start` on, leaving out the project
+    // history spliced in, which belongs in the project's own file.
+    fn global_entries(&self, start: usize) -> Vec<histfile::FileEntry> {
+        let spliced = self.project.as_ref().map(|project| project.entries.clone()).unwrap_or_default();
+        self.editor.history().iter()
+            .zip(&self.history_times)
+            .enumerate()
+            .skip(start)
+            .filter(|(i, _)| !spliced.contains(&(self.history_dropped + i)))
+            .map(|(_, (line, time))| histfile::FileEntry { line: line.clone(), time: *time })
+            .collect()
+    }
+
+    // Adds the entries not yet written to a history file to it, as
+    // `history -a` and exiting do.
+    fn append_history(&mut self, path: &Path) -> Result<()> {
+        self.merge_history(path, false, true).map_err(|e| {
+            eprintln!("Error writing to history file {}: {}", path.display(), e);
+            ReadlineError::Io(e)
+        })
+    }
+
+    // Other shells may be writing to the same history file, so it is
+    // worked on under a lock and only added to, apart from trimming it to
+    // HISTFILESIZE. With `import`, the entries other shells added since it
+    // was last read are taken into the history list, as `history -n` and
+    // share_history do; with `append`, this session's entries not yet in
+    // it go on the end. Only the part not read before is read, and nothing
+    // is written unless this session has something new.
+    fn merge_history(&mut self, path: &Path, import: bool, append: bool) -> std::io::Result<()> {
+        let _lock = histfile::lock(path)?;
+        let appended = self.history_append_files.get(path).copied().unwrap_or(0);
+        // Taken before any entries are read in, which are in the file already.
+        let own = if append { self.global_entries(appended.saturating_sub(self.history_dropped)) } else { Vec::new() };
+
+        let previous = self.history_files.remove(path);
+        let seen = previous.as_ref().map(|file| (file.inode, file.offset));
+        let tail = match histfile::read_tail(path, seen) {
+            Ok(tail) => Some(tail),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && append => None,
             Err(e) => {
-                eprintln!("Error opening file {}: {}", path.display(), e);
-                Err(rustyline::error::ReadlineError::Io(e))
+                self.history_files.extend(previous.map(|file| (path.to_owned(), file)));
+                return Err(e);
+            }
+        };
+        let entries = tail.as_ref().map(|tail| histfile::parse_file(&tail.text, histfile::now())).unwrap_or_default();
+        let restarted = tail.as_ref().map_or(true, |tail| tail.restarted);
+        let mut file = previous.unwrap_or(HistoryFile { inode: 0, offset: 0, count: 0, known: HashSet::new() });
+        let mut count = if restarted { 0 } else { file.count } + entries.len();
+
+        let unread: Vec<&histfile::FileEntry> =
+            entries.iter().filter(|entry| !file.known.contains(&(entry.time, entry.line.clone()))).collect();
+        if import {
+            for entry in &unread {
+                self.push_history(&entry.line, entry.time);
+                file.known.insert((entry.time, entry.line.clone()));
+            }
+        }
+        // Entries left unread stay for `history -n`, so the position moves
+        // on only when there are none.
+        let caught_up = import || unread.is_empty();
+        if let (Some(tail), true) = (&tail, caught_up) {
+            file.inode = tail.inode;
+            file.offset = tail.len;
+            file.count = count;
+        }
+
+        let redactor = self.redactor();
+        let new: Vec<histfile::FileEntry> = own
+            .into_iter()
+            .filter_map(|entry| Some(histfile::FileEntry { line: redactor.redact(&entry.line)?, time: entry.time }))
+            .filter(|entry| !file.known.contains(&(entry.time, entry.line.clone())))
+            .collect();
+        if !new.is_empty() {
+            histfile::append_entries(path, &new)?;
+            count += new.len();
+            file.known.extend(new.iter().map(|entry| (entry.time, entry.line.clone())));
+            let value = self.vars.get("HISTFILESIZE").or(self.vars.get("HISTSIZE"));
+            let written = if count > history_limit(value).unwrap_or(usize::MAX) {
+                let contents = std::fs::read_to_string(path)?;
+                let rewritten = self.write_history_file(path, &histfile::parse_file(&contents, histfile::now()))?;
+                // Entries trimmed off are no longer in the file.
+                file.known.retain(|entry| rewritten.known.contains(entry));
+                rewritten
+            } else {
+                let metadata = std::fs::metadata(path)?;
+                HistoryFile { inode: metadata.ino(), offset: metadata.len(), count, known: HashSet::new() }
+            };
+            // Otherwise the old position stays, and a file replaced by
+            // trimming is read again from the start.
+            if caught_up {
+                file = HistoryFile { known: file.known, ..written };
             }
         }
+        self.history_files.insert(path.to_owned(), file);
+        // Entries read in are known to be in the file, so they are not
+        // written back when later ones are added.
+        if append {
+            let end = self.history_dropped + self.editor.history().len();
+            self.history_append_files.insert(path.to_owned(), end);
+        }
+        Ok(())
     }
 
-    fn append_history(&mut self, path: &PathBuf) -> Result<()> {
-        let history = self.editor.history();
+    // Replaces a history file with its last HISTFILESIZE entries, or
+    // HISTSIZE entries if HISTFILESIZE is unset. The caller holds the lock.
+    fn write_history_file(&self, path: &Path, entries: &[histfile::FileEntry]) -> std::io::Result<HistoryFile> {
+        let value = self.vars.get("HISTFILESIZE").or(self.vars.get("HISTSIZE"));
+        let limit = history_limit(value).unwrap_or(usize::MAX);
+        // Entries read from older files may still hold secrets.
+        let redactor = self.redactor();
+        let entries: Vec<(String, i64)> = entries.iter()
+            .filter_map(|entry| Some((redactor.redact(&entry.line)?, entry.time)))
+            .collect();
+        let kept = &entries[entries.len().saturating_sub(limit)..];
+        let contents: String = kept.iter().map(|(line, time)| histfile::format_entry(line, *time)).collect();
+        histfile::write_atomic(path, &contents)?;
+        let metadata = std::fs::metadata(path)?;
+        Ok(HistoryFile {
+            inode: metadata.ino(),
+            offset: metadata.len(),
+            count: kept.len(),
+            known: kept.iter().map(|(line, time)| (*time, line.clone())).collect(),
+        })
+    }
 
-        let mut start_index = 0;
-        if self.history_append_files.contains_key(path) {
-            start_index = *self.history_append_files.get(path).unwrap();
+    // Adds an entry to the history list with the time it was run. Returns
+    // false if the editor did not take it.
+    fn push_history(&mut self, line: &str, time: i64) -> bool {
+        if !self.editor.add_history_entry(line) {
+            return false;
         }
-        
-        // 2. Open the file in **append mode**.
-        match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path) 
-        {
-            Ok(mut file) => {
-                // 3. Iterate over in-memory history, skipping the entries already present in the file.
-                // This is the idiomatic way to achieve the check: if i >= start_index
-                for (i, entry) in history.iter().enumerate(){
+        self.history_times.push(time);
+        self.trim_history_times();
+        true
+    }
 
-                    if i < start_index
-                    {
-                        continue;
-                    }
+    // Drops the times of entries the editor let go of to stay within its
+    // size limit.
+    fn trim_history_times(&mut self) {
+        let excess = self.history_times.len().saturating_sub(self.editor.history().len());
+        self.history_times.drain(..excess);
+        self.history_dropped += excess;
+    }
 
-                    if entry.starts_with("#") {
-                        continue;
-                    }
+    // Appends the entries of a history file to the history list.
+    fn load_history_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let tail = histfile::read_tail(path, None)?;
+        let entries = histfile::parse_file(&tail.text, histfile::now());
+        for entry in &entries {
+            self.push_history(&entry.line, entry.time);
+        }
+        let known = entries.iter().map(|entry| (entry.time, entry.line.clone())).collect();
+        let file = HistoryFile { inode: tail.inode, offset: tail.len, count: entries.len(), known };
+        self.history_files.insert(path.to_owned(), file);
+        Ok(())
+    }
 
-                    if let Err(e) = writeln!(file, "{}", entry) {
-                        eprintln!("Error writing to history file {}: {}", path.display(), e);
-                        // Return error if writing fails
-                        return Err(ReadlineError::Io(e)); 
-                    }
-                }
+    // Reads the entries of a history file that are new since it was last
+    // read or written, as `history -n` does.
+    fn read_new_history(&mut self, path: &Path) -> std::io::Result<()> {
+        self.merge_history(path, true, false)
+    }
 
-                self.history_append_files.insert(path.to_owned(), history.len());
-                
-                // Optional logging to confirm success
-                Ok(())
+    // Deletes a range of history entries.
+    fn remove_history(&mut self, range: std::ops::Range<usize>) {
+        self.retain_history(|i, _| !range.contains(&i));
+    }
+
+    // Keeps the history entries for which `keep` returns true. rustyline
+    // cannot remove entries, so the list is rebuilt without the others.
+    fn retain_history(&mut self, keep: impl Fn(usize, &str) -> bool) {
+        let entries: Vec<String> = self.editor.history().iter().cloned().collect();
+        let times = std::mem::take(&mut self.history_times);
+        self.editor.clear_history();
+        let mut removed = Vec::new();
+        for (i, (entry, time)) in entries.iter().zip(times).enumerate() {
+            if keep(i, entry) {
+                self.push_history(entry, time);
+            } else {
+                removed.push(i);
             }
-            Err(e) => {
-                eprintln!("Error opening history file {}: {}", path.display(), e);
-                // Return error if opening fails
-                Err(ReadlineError::Io(e))
+        }
+
+        // Keep `history -a` from skipping entries that moved down.
+        let dropped = self.history_dropped;
+        let shift = |position: usize| position - removed.iter().filter(|i| dropped + **i < position).count();
+        for appended in self.history_append_files.values_mut() {
+            *appended = shift(*appended);
+        }
+        if let Some(project) = &mut self.project {
+            project.entries = shift(project.entries.start)..shift(project.entries.end);
+        }
+        self.current_entry = self
+            .current_entry
+            .filter(|position| !removed.iter().any(|i| dropped + i == *position))
+            .map(shift);
+    }
+
+    // Takes the line being run back out of the history list, for commands
+    // such as `history -s` that stand in for their own line. Nothing is
+    // removed if the line was never stored.
+    fn remove_current_entry(&mut self) {
+        if let Some(index) = self.current_entry_index() {
+            self.remove_history(index..index + 1);
+        }
+        self.current_entry = None;
+    }
+
+    // Where the line being run is in the history list, if it was stored
+    // and is still there.
+    fn current_entry_index(&self) -> Option<usize> {
+        let index = self.current_entry?.checked_sub(self.history_dropped)?;
+        (index < self.editor.history().len()).then_some(index)
+    }
+
+    // The rules that keep secrets out of history, as configured now. They
+    // are compiled again only when HISTREDACT or HISTREDACT_DROP changes,
+    // and an invalid pattern is reported when it first appears.
+    fn redactor(&self) -> Rc<redact::Redactor> {
+        let rules = (self.vars.values("HISTREDACT"), self.vars.values("HISTREDACT_DROP"));
+        let mut cache = self.redactor.borrow_mut();
+        match &*cache {
+            Some((cached, redactor)) if *cached == rules => redactor.clone(),
+            previous => {
+                let redactor = Rc::new(redact::Redactor::new(&rules.0, &rules.1));
+                let reported = previous.as_ref().map(|(_, previous)| previous.invalid.as_slice()).unwrap_or_default();
+                for message in redactor.invalid.iter().filter(|message| !reported.contains(message)) {
+                    eprintln!("{}", message);
+                }
+                *cache = Some((rules, redactor.clone()));
+                redactor
+            }
+        }
+    }
+
+    // The line without the prefix that keeps a command out of history:
+    // $HISTOPTOUT, or `nohist ` if it is unset. None if it does not start
+    // with it.
+    fn strip_opt_out<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let prefix = self.vars.get("HISTOPTOUT").unwrap_or("nohist ");
+        if prefix.is_empty() {
+            return None;
+        }
+        line.strip_prefix(prefix)
+    }
+
+    // Whether HISTCONTROL, as set now, has the option `name`.
+    fn history_control(&self, name: &str) -> bool {
+        let control: Vec<&str> = self.vars.get("HISTCONTROL").unwrap_or_default().split(':').collect();
+        control.contains(&name)
+            || (control.contains(&"ignoreboth") && (name == "ignorespace" || name == "ignoredups"))
+    }
+
+    // Whether the user has asked to keep `line` out of history, with
+    // HISTCONTROL's ignorespace or a HISTIGNORE pattern. Duplicates are
+    // not counted here. Secrets are masked first and `line` is the text as
+    // it would be stored, so patterns match what history would keep.
+    fn history_ignores(&self, line: &str) -> bool {
+        if self.history_control("ignorespace") && line.starts_with(char::is_whitespace) {
+            return true;
+        }
+        // In HISTIGNORE patterns, `&` stands for the previous history line.
+        let ignore = self.vars.get("HISTIGNORE").unwrap_or_default();
+        let previous = glob::escape(self.editor.history().last().map(String::as_str).unwrap_or_default());
+        ignore
+            .split(':')
+            .filter(|pattern| !pattern.is_empty())
+            .any(|pattern| glob::glob_match(&pattern.replace('&', &previous), line))
+    }
+
+    // Adds an interactive line to the history list, following HISTCONTROL
+    // and HISTIGNORE as they are set now, with secrets masked.
+    fn add_history(&mut self, line: &str) {
+        let Some(line) = self.redactor().redact(line) else {
+            return;
+        };
+        let line = line.as_str();
+        self.apply_history_size();
+        if self.history_ignores(line) {
+            return;
+        }
+        if self.history_control("ignoredups") && self.editor.history().last().map(String::as_str) == Some(line) {
+            return;
+        }
+        if self.history_control("erasedups") {
+            self.retain_history(|_, entry| entry != line);
+        }
+        let time = histfile::now();
+        if self.push_history(line, time) {
+            self.current_entry = Some(self.history_dropped + self.editor.history().len() - 1);
+            if let Some(project) = &self.project {
+                let _ = histfile::append_entry(&project.file, line, time);
             }
         }
     }
 
+    // The nearest directory above the current one holding one of the
+    // HISTPROJECT_MARKERS (colon-separated, `.git` by default), if
+    // project_history is on.
+    fn project_root(&self) -> Option<PathBuf> {
+        if !self.shopt_enabled("project_history") {
+            return None;
+        }
+        let markers = self.vars.get("HISTPROJECT_MARKERS").unwrap_or(".git");
+        let dir = builtins::current_dir(self, false).ok()?;
+        dir.ancestors()
+            .find(|dir| markers.split(':').any(|marker| !marker.is_empty() && dir.join(marker).exists()))
+            .map(Path::to_path_buf)
+    }
+
+    // Swaps the project history in the history list after the current
+    // directory or the project_history option changed. The project's
+    // entries go after the global ones, so that Up and Ctrl-R find them
+    // first.
+    fn update_project_history(&mut self) {
+        let root = self.project_root();
+        if root.as_ref() == self.project.as_ref().map(|project| &project.root) {
+            return;
+        }
+        if let Some(project) = self.project.take() {
+            let dropped = self.history_dropped;
+            self.retain_history(|i, _| !project.entries.contains(&(dropped + i)));
+        }
+        let Some(root) = root else {
+            return;
+        };
+        let Some(file) = histfile::project_file(self.vars.get("HISTPROJECTDIR"), &root) else {
+            return;
+        };
+        let entries = std::fs::read_to_string(&file)
+            .map(|contents| histfile::parse_file(&contents, histfile::now()))
+            .unwrap_or_default();
+        let start = self.history_dropped + self.editor.history().len();
+        for entry in &entries {
+            self.push_history(&entry.line, entry.time);
+        }
+        let end = self.history_dropped + self.editor.history().len();
+        self.project = Some(ProjectHistory { root, file, entries: start..end });
+    }
+
+    // Resizes the history list if HISTSIZE has changed.
+    fn apply_history_size(&mut self) {
+        let size = history_limit(self.vars.get("HISTSIZE")).unwrap_or(usize::MAX);
+        if size != self.history_size {
+            self.history_size = size;
+            self.editor.set_max_history_size(size);
+            self.trim_history_times();
+        }
+    }
+
+    // Adds a command that was run to the structured history store.
+    fn record_command(&mut self, line: &str, start: i64, cwd: String, duration: std::time::Duration) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let Some(line) = self.redactor().redact(line) else {
+            return;
+        };
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.record_run(&line, &cwd, self.last_status);
+        }
+        let Some(path) = histdb::path(self.vars.get("HISTDB")) else {
+            return;
+        };
+        let record = histdb::Record {
+            start,
+            duration_ms: duration.as_millis() as u64,
+            cwd,
+            status: self.last_status,
+            host: histdb::hostname(),
+            session: self.session_id.clone(),
+            command: line,
+        };
+        let _ = histdb::append(&path, &record);
+    }
+
+    // With inc_append_history or share_history, adds each command to
+    // HISTFILE as soon as it has run instead of on exit.
+    fn write_history_now(&mut self) {
+        let share = self.shopt_enabled("share_history");
+        if !share && !self.shopt_enabled("inc_append_history") {
+            return;
+        }
+        if let Some(path) = Self::default_history_path() {
+            let _ = self.merge_history(&path, share, true);
+        }
+    }
+
     fn save_history_default(&mut self) -> Result<()> {
+        if !self.interactive {
+            return Ok(());
+        }
         if let Some(path) = Self::default_history_path()
         {
-            let _ =self.save_history(&path);
+            let _ =self.append_history(&path);
         }
         Ok(())
     }
 
+    // Applies history expansion to an interactive line. Returns None if the
+    // line should not be run: expansion failed, `:p` asked for it only to be
+    // printed, or histverify put it in `pending` for editing.
+    fn expand_history(&mut self, line: String, pending: &mut Option<String>) -> Option<String> {
+        let entries: Vec<String> = self.editor.history().iter().cloned().collect();
+        let expansion = match history::expand(&line, &entries, self.history_dropped) {
+            Ok(expansion) => expansion,
+            Err(message) => {
+                eprintln!("{}", message);
+                self.last_status = 1;
+                return None;
+            }
+        };
+        if !expansion.expanded {
+            return Some(expansion.line);
+        }
+        if expansion.print_only {
+            println!("{}", expansion.line);
+            self.add_history(&expansion.line);
+            return None;
+        }
+        if self.shopt_enabled("histverify") {
+            *pending = Some(expansion.line);
+            return None;
+        }
+        println!("{}", expansion.line);
+        Some(expansion.line)
+    }
+
     fn run(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> 
     {
         let prompt = "$ ";
+        self.load_history();
+        self.load_runs();
+        // An expanded line that histverify put back for editing.
+        let mut pending: Option<String> = None;
         loop
         {
-            let readline = self.editor.readline(prompt);
+            // HISTSIZE may have been assigned by the last command.
+            self.apply_history_size();
+            self.update_helper();
+            if self.shopt_enabled("share_history") {
+                if let Some(path) = Self::default_history_path() {
+                    let _ = self.merge_history(&path, true, false);
+                }
+            }
+            let readline = match pending.take() {
+                Some(text) => self.editor.readline_with_initial(prompt, (&text, "")),
+                None => self.editor.readline(prompt),
+            };
             match readline {
                 Ok(line) => {
+                    self.current_entry = None;
+                    let Some(line) = self.expand_history(line, &mut pending) else {
+                        continue;
+                    };
+                    // A command with the opt-out prefix is run without it
+                    // and kept out of history.
+                    let (line, opted_out) = match self.strip_opt_out(&line) {
+                        Some(stripped) => (stripped.to_string(), true),
+                        None => (line, false),
+                    };
+                    // Lines kept out of history stay out of the structured
+                    // store as well; duplicates are still recorded there.
+                    let ignored = opted_out
+                        || self.redactor().redact(&line).map_or(true, |stored| self.history_ignores(&stored));
                     // Add non-empty commands to history
-                    if !line.trim().is_empty() {
-                        self.editor.add_history_entry(line.as_str());
+                    if !line.trim().is_empty() && !opted_out {
+                        self.add_history(&line);
                     }
+                    let started = histfile::now();
+                    let timer = std::time::Instant::now();
+                    let cwd = self.vars.get("PWD").unwrap_or_default().to_string();
                     run_command(self, &line);
+                    if !ignored {
+                        self.record_command(&line, started, cwd, timer.elapsed());
+                    }
+                    self.update_project_history();
+                    self.write_history_now();
                 },
                 Err(ReadlineError::Interrupted) => {
                     // Ctrl-C
@@ -177,64 +881,281 @@ impl Shell {
 }
 
 
+// Ctrl-X Ctrl-E: opens the line being typed in $VISUAL or $EDITOR and
+// puts the edited text back into the buffer.
+struct EditInEditor {
+    // The terminal settings from before rustyline switched to raw mode,
+    // for the editor to run with.
+    terminal: Option<libc::termios>,
+    // The editor command, as the shell last worked it out.
+    editor: Arc<Mutex<String>>,
+}
+
+impl EditInEditor {
+    fn new(editor: Arc<Mutex<String>>) -> Self {
+        let mut terminal: libc::termios = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::isatty(0) == 1 && libc::tcgetattr(0, &mut terminal) == 0 };
+        EditInEditor { terminal: ok.then_some(terminal), editor }
+    }
+
+    fn edit(&self, line: &str) -> Option<String> {
+        let editor = self.editor.lock().unwrap().clone();
+        let (path, mut file) = histfile::temp_file("line", ".sh").ok()?;
+        if file.write_all(format!("{}\n", line).as_bytes()).is_err() {
+            let _ = std::fs::remove_file(&path);
+            return None;
+        }
+        drop(file);
+
+        let mut raw: libc::termios = unsafe { std::mem::zeroed() };
+        let restore = self.terminal.filter(|_| unsafe { libc::tcgetattr(0, &mut raw) } == 0);
+        if let Some(terminal) = &restore {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, terminal) };
+        }
+        // The editor may take arguments, so let sh split it.
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&path)
+            .status();
+        if restore.is_some() {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, &raw) };
+        }
+
+        let edited = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        if !status.ok()?.success() {
+            return None;
+        }
+        let edited = edited.ok()?;
+        Some(edited.strip_suffix('\n').unwrap_or(&edited).to_string())
+    }
+}
+
+impl ConditionalEventHandler for EditInEditor {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        match self.edit(ctx.line()) {
+            Some(text) => Some(Cmd::Replace(Movement::WholeBuffer, Some(text))),
+            // Leave the line as it was.
+            None => Some(Cmd::Noop),
+        }
+    }
+}
+
+// End and Alt-F at the end of the line: take the whole suggestion, or its
+// next word. Elsewhere the keys keep their usual meaning.
+struct AcceptHint {
+    one_word: bool,
+}
+
+impl ConditionalEventHandler for AcceptHint {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let hint = ctx.hint_text().filter(|_| ctx.pos() == ctx.line().len())?;
+        if !self.one_word {
+            return Some(Cmd::CompleteHint);
+        }
+        let start = hint.len() - hint.trim_start().len();
+        let end = hint[start..].find(char::is_whitespace).map_or(hint.len(), |i| start + i);
+        Some(Cmd::Insert(1, hint[..end].to_string()))
+    }
+}
+
 #[derive(Default)]
 struct ShellHelper{
-    all_commands: Vec<String>
+    all_commands: Vec<String>,
+    // The colours for the input line, or None to leave it uncoloured.
+    palette: Option<highlight::Palette>,
+    functions: HashSet<String>,
+    // Where and how each command line has run, for choosing suggestions.
+    runs: HashMap<String, CommandRuns>,
+    cwd: String,
+    // The shell's variables, for paths that start with one.
+    vars: HashMap<String, String>,
+    // The directory last listed for completion and its entries, kept for
+    // the rest of the line so that suggesting a completion on every
+    // keystroke does not read it each time.
+    listing: RefCell<Option<(String, Vec<complete::Entry>)>>,
+}
+
+#[derive(Default)]
+struct CommandRuns {
+    dirs: HashSet<String>,
+    // Whether the last run exited with status zero.
+    succeeded: bool,
+}
+
+impl ShellHelper {
+    fn record_run(&mut self, line: &str, cwd: &str, status: i32) {
+        let runs = self.runs.entry(line.to_string()).or_default();
+        runs.dirs.insert(cwd.to_string());
+        runs.succeeded = status == 0;
+    }
+
+    // Lists a directory for completion, from the cache if it was the last
+    // one listed.
+    fn list_dir(&self, dir: &str) -> Option<Vec<complete::Entry>> {
+        if let Some((listed, entries)) = &*self.listing.borrow() {
+            if listed == dir {
+                return Some(entries.clone());
+            }
+        }
+        let entries = complete::list_dir(dir)?;
+        *self.listing.borrow_mut() = Some((dir.to_string(), entries.clone()));
+        Some(entries)
+    }
+
+    // Whether `name` would run something: a builtin, a function or a
+    // program.
+    fn resolves(&self, name: &str) -> bool {
+        if name.contains('/') {
+            return is_executable(Path::new(name));
+        }
+        BUILTINS.contains(&name) || self.functions.contains(name) || self.all_commands.iter().any(|command| command == name)
+    }
 }
 
 impl Completer for ShellHelper {
     type Candidate = CompletionPair;
     fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Result<(usize, Vec<CompletionPair>)> {
 
-        let start = line[..pos]
-            .rfind(|c: char| c.is_whitespace())
-            .map_or(0, |i| i + 1);
-        
-        let prefix = &line[start..pos];
-
-        let mut candidates: Vec<CompletionPair> = self.all_commands
-            .iter()
-            .filter(|cmd| cmd.starts_with(prefix))
-            .map(|cmd| CompletionPair {
-                display: cmd.to_string(),
-                replacement: format!("{} ", cmd)
+        let word = lexer::partial_word(&line[..pos]);
+        let prefix = word.value.as_str();
+
+        // Only command names are completed in command position, unless
+        // the word is a path.
+        let position = complete::position(&line[..word.start]);
+        let candidates = if position != complete::Position::Command || prefix.contains('/') {
+            // Nothing is expanded inside single quotes.
+            let var = |name: &str| (word.quote != Some('\'')).then(|| self.vars.get(name).cloned()).flatten();
+            complete::files(prefix, position == complete::Position::Directory, &|dir| self.list_dir(dir), &var)
+        } else {
+            let mut names: Vec<&String> = self.all_commands.iter().filter(|cmd| cmd.starts_with(prefix)).collect();
+            names.sort();
+            names.into_iter()
+                .map(|cmd| complete::Candidate { literal: String::new(), value: cmd.clone(), display: cmd.clone(), is_dir: false })
+                .collect()
+        };
+
+        // In the middle of a line, the blank after the word may already be there.
+        let blank_follows = line[pos..].starts_with(char::is_whitespace);
+        let candidates = candidates
+            .into_iter()
+            .map(|candidate| CompletionPair {
+                replacement: complete::replacement(&candidate, word.quote, blank_follows),
+                display: candidate.display,
             })
             .collect();
-        candidates.sort_by(|a, b| a.display.cmp(&b.display));
 
-        Ok((start, candidates))
+        Ok((word.start, candidates))
     }
 }
 
 
 // 3. Implement required Helper traits using default/empty implementations
 impl Helper for ShellHelper {}
+
+// Suggests the rest of the most recent history entry that starts with the
+// line, preferring entries run in the current directory and ones that
+// succeeded. Failing that, the completion if there is only one.
 impl Hinter for ShellHelper {
     type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if line.is_empty() || pos < line.len() {
+            return None;
+        }
+        let history = ctx.history();
+        let mut best: Option<(usize, &str)> = None;
+        for entry in (0..history.len()).rev().filter_map(|index| history.get(index)) {
+            let Some(rest) = entry.strip_prefix(line) else {
+                continue;
+            };
+            if rest.is_empty() || rest.contains('\n') {
+                continue;
+            }
+            let score = self.runs.get(entry.as_str()).map_or(0, |runs| {
+                runs.dirs.contains(&self.cwd) as usize + runs.succeeded as usize
+            });
+            if best.map_or(true, |(best_score, _)| score > best_score) {
+                best = Some((score, rest));
+                if score == 2 {
+                    break;
+                }
+            }
+        }
+        if let Some((_, rest)) = best {
+            return Some(rest.to_string());
+        }
+        let (start, candidates) = self.complete(line, pos, ctx).ok()?;
+        match candidates.as_slice() {
+            // Only if it carries on from what was typed, quotes and all.
+            [only] => only.replacement.strip_prefix(&line[start..pos]).filter(|rest| !rest.is_empty()).map(str::to_string),
+            _ => None,
+        }
+    }
+}
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        match &self.palette {
+            Some(palette) => Cow::Owned(highlight::highlight(line, pos, palette, &|name| self.resolves(name))),
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        match &self.palette {
+            Some(palette) => Cow::Owned(palette.hint(hint)),
+            None => Cow::Borrowed(hint),
+        }
+    }
+
+    // Moving the cursor can change which brackets are shown as a pair.
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        self.palette.is_some()
+    }
+}
+// Asks for more lines while the parser says the command is unfinished, so
+// that it is edited, run and stored in history as one entry. rustyline 10
+// draws no prompt on the later lines of a multi-line buffer, so they are
+// shown without $PS2.
+impl Validator for ShellHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> Result<ValidationResult> {
+        if parser::is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
 }
-impl Highlighter for ShellHelper {}
-impl Validator for ShellHelper {}
 
-fn find_executable_in_path(name: &str) -> Option<PathBuf>
+fn find_executable_in_path(name: &str, path_var: &str) -> Option<PathBuf>
 {
-    env::var("PATH").ok().and_then(|path_var| {
-        for path in env::split_paths(&path_var)
+    find_all_in_path(name, path_var).into_iter().next()
+}
+
+// Every executable called `name` in `path_var`, the shell's PATH, in PATH
+// order. A name with a slash in it is a path of its own and is not
+// searched for.
+fn find_all_in_path(name: &str, path_var: &str) -> Vec<PathBuf>
+{
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) { vec![path] } else { Vec::new() };
+    }
+    let mut found = Vec::new();
+    for path in env::split_paths(path_var)
+    {
+        let full_path = path.join(name);
+        if is_executable(&full_path) && !found.contains(&full_path)
         {
-            let full_path = path.join(name);
-            if full_path.is_file()
-            {
-                if let Ok(metadata) = full_path.metadata() {
-                    if metadata.permissions().mode() & 0o111 != 0 {
-                        return Some(full_path);
-                    }
-                }
-            }
+            found.push(full_path);
         }
-        None
-    })
+    }
+    found
 }
 
-fn is_executable(path: &PathBuf) -> bool {
+fn is_executable(path: &Path) -> bool {
     if path.is_file() {
         if let Ok(metadata) = path.metadata() {
             // Check if the executable bit is set for the owner, group, or others (0o111)
@@ -244,23 +1165,40 @@ fn is_executable(path: &PathBuf) -> bool {
     false
 }
 
-// Scans the PATH environment variable and returns a Vec of all executable file names.
-fn get_executables_in_path() -> Vec<String> {
+// The number of history entries a HISTSIZE or HISTFILESIZE value allows:
+// None (no limit) if it is negative or not a number, Some(500) if unset.
+fn history_limit(value: Option<&str>) -> Option<usize> {
+    match value {
+        None => Some(500),
+        Some(value) => value.trim().parse().ok(),
+    }
+}
+
+// The command names offered for completion: programs in PATH and builtins.
+fn completion_commands(path_var: &str) -> Vec<String> {
+    let mut all_commands = get_executables_in_path(path_var);
+    for builtin in BUILTINS {
+        if !all_commands.contains(&builtin.to_string()) {
+            all_commands.push(builtin.to_string());
+        }
+    }
+    all_commands
+}
+
+// Scans the PATH value `path_var` and returns a Vec of all executable file names.
+fn get_executables_in_path(path_var: &str) -> Vec<String> {
     let mut executables = Vec::new();
     
-    if let Ok(path_var) = env::var("PATH") {
+    for path_dir in env::split_paths(path_var) {
         
-        for path_dir in env::split_paths(&path_var) {
+        if let Ok(entries) = std::fs::read_dir(&path_dir) {
             
-            if let Ok(entries) = std::fs::read_dir(&path_dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
                 
-                for entry in entries.filter_map(|e| e.ok()) {
-                    let path = entry.path();
-                    
-                    if is_executable(&path) {
-                        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
-                            executables.push(file_name.to_string());
-                        }
+                if is_executable(&path) {
+                    if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+                        executables.push(file_name.to_string());
                     }
                 }
             }
@@ -270,11 +1208,22 @@ fn get_executables_in_path() -> Vec<String> {
 }
 
 
-fn handle_built_in_output(std_out_s: &str, std_out: Option<String>, std_out_append: bool, std_err_s: &str, std_err: Option<String>, std_err_append: bool) {
+// Where a command's standard input, output and error should go, as
+// given by its redirection operators.
+#[derive(Default)]
+struct Redirections {
+    std_in_file: Option<String>,
+    std_out_file: Option<String>,
+    std_out_append: bool,
+    std_err_file: Option<String>,
+    std_err_append: bool,
+}
 
-    if let Some(file_path) = std_out {
+fn handle_built_in_output(std_out_s: &str, std_err_s: &str, redirections: &Redirections) {
 
-        match OpenOptions::new().write(true).append(std_out_append).create(true).truncate(!std_out_append).open(&file_path) {
+    if let Some(file_path) = &redirections.std_out_file {
+        let append = redirections.std_out_append;
+        match OpenOptions::new().write(true).append(append).create(true).truncate(!append).open(file_path) {
             Ok(mut file) => {
                 if !std_out_s.is_empty() && write!(file, "{}", std_out_s).is_err() {
                     eprintln!("Error writing to file {}", file_path);
@@ -284,14 +1233,13 @@ fn handle_built_in_output(std_out_s: &str, std_out: Option<String>, std_out_appe
                 eprintln!("Error opening file {}: {}", file_path, e);
             }
         }
-    } else {
-        if !std_out_s.is_empty() {
-            print!("{}", std_out_s);
-        }
+    } else if !std_out_s.is_empty() {
+        print!("{}", std_out_s);
     }
 
-    if let Some(file_path) = std_err {
-        match OpenOptions::new().write(true).append(std_err_append).create(true).truncate(!std_err_append).open(&file_path) {
+    if let Some(file_path) = &redirections.std_err_file {
+        let append = redirections.std_err_append;
+        match OpenOptions::new().write(true).append(append).create(true).truncate(!append).open(file_path) {
             Ok(mut file) => {
                 if !std_err_s.is_empty()
                 {
@@ -304,379 +1252,644 @@ fn handle_built_in_output(std_out_s: &str, std_out: Option<String>, std_out_appe
                 eprintln!("Error opening file {}: {}", file_path, e);
             }
         }
-    } else {
-        if !std_err_s.is_empty()
-        {
-            eprint!("{}", std_err_s);
+    } else if !std_err_s.is_empty()
+    {
+        eprint!("{}", std_err_s);
+    }
+}
+
+fn run_command(shell: &mut Shell, input: &str){
+
+    match parser::parse(input) {
+        Ok(list) => execute_list(shell, &list),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            shell.last_status = 2;
         }
     }
 }
 
-fn change_directory(path: &str)
-{
-    // if it is absolute path, check if the directory is exist
-    let target_path = if path == "~" {
-        match env::var("HOME")
-        {
-            Ok(home_dir) => PathBuf::from(home_dir),
-            Err(_) =>
-            {
-                eprintln!("cd: HOME not set");
-                return;
+fn execute_list(shell: &mut Shell, list: &[parser::AndOr]) {
+    for and_or in list {
+        execute_pipeline(shell, &and_or.first);
+        for (connector, pipeline) in &and_or.rest {
+            if shell.flow != Flow::Normal {
+                break;
+            }
+            let succeeded = shell.last_status == 0;
+            if (*connector == Connector::And) == succeeded {
+                execute_pipeline(shell, pipeline);
             }
         }
-    } else {
-        PathBuf::from(path)
-    };
-
-    if env::set_current_dir(&target_path).is_err(){
-        eprintln!("cd: {}: No such file or directory", path);
+        if shell.flow != Flow::Normal {
+            break;
+        }
     }
 }
 
-fn arg_parse(line: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current_arg = String::new();
-    let mut quote_char = None;
-    let mut escaped = false;
+fn execute_pipeline(shell: &mut Shell, pipeline: &Pipeline) {
+    let commands = &pipeline.commands;
 
-    for c in line.chars() {
-        if escaped {
-            if quote_char == Some('"')
-            {
-                if c == '"' || c == '\\' {
-                    current_arg.push(c);
-                }
-                else
-                {
-                    current_arg.push('\\');
-                    current_arg.push(c);
+    if commands.len() == 1 && !matches!(commands[0], Command::Simple { .. }) {
+        execute_compound(shell, &commands[0]);
+    } else {
+        let mut prev_output: Option<OwnedFd> = None;
+
+        for (ith_command, command) in commands.iter().enumerate() {
+            let is_last = ith_command == commands.len() - 1;
+
+            prev_output = match command {
+                Command::Simple { words, redirects } => {
+                    match run_simple_command(shell, words, redirects, prev_output.take(), is_last) {
+                        Ok(output) => output,
+                        Err(()) => break,
+                    }
                 }
-            }
-            else
-            {
-                current_arg.push(c);
-            }
-            escaped = false;
+                // Compound commands in a pipeline run in a child of their own.
+                _ => run_forked(shell, prev_output.take(), !is_last, |shell| {
+                    execute_compound(shell, command);
+                    shell.last_status
+                }),
+            };
         }
-        else if c == '\\' &&  quote_char != Some('\'') {
-            escaped = true;
+        wait_pipeline_children(shell);
+    }
+
+    if pipeline.negated {
+        shell.last_status = (shell.last_status == 0) as i32;
+    }
+    // A PATH assigned by the pipeline empties the hash table right away,
+    // rather than at the next lookup, so that completion follows it too.
+    shell.check_hash_path();
+}
+
+// Runs a compound command or function definition in the current shell.
+fn execute_compound(shell: &mut Shell, command: &Command) {
+    match command {
+        Command::Simple { words, redirects } => {
+            let _ = run_simple_command(shell, words, redirects, None, true);
         }
-        else if c == '"' || c == '\'' {
-            match quote_char {
-                None =>
-                {
-                    quote_char = Some(c);
-                }
-                Some(q) if q == c =>
-                {
-                    quote_char = None;
-                }
-                Some (_) => {
-                    current_arg.push(c);
-                }
-            }
+        Command::Group { body, redirects } => {
+            let Ok(redirections) = resolve_redirections(shell, redirects) else {
+                return;
+            };
+            with_redirections(shell, &redirections, |shell| execute_list(shell, body));
         }
-        else if c.is_whitespace() && !quote_char.is_some()
-        {
-            if !current_arg.is_empty()
-            {
-                // use mem::take to efficently move the string
-                args.push(std::mem::take(&mut current_arg));
-            }
+        Command::Subshell { body, redirects } => {
+            let Ok(redirections) = resolve_redirections(shell, redirects) else {
+                return;
+            };
+            run_forked(shell, None, false, |shell| {
+                with_redirections(shell, &redirections, |shell| execute_list(shell, body));
+                shell.last_status
+            });
         }
-        else
-        {
-            current_arg.push(c);
+        Command::Conditional { words, redirects } => {
+            let Ok(redirections) = resolve_redirections(shell, redirects) else {
+                return;
+            };
+            with_redirections(shell, &redirections, |shell| {
+                shell.last_status = match conditional::conditional(shell, words) {
+                    Ok(result) => !result as i32,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        2
+                    }
+                };
+            });
+        }
+        Command::FunctionDef { name, body } => {
+            shell.functions.insert(name.clone(), body.clone());
+            shell.last_status = 0;
         }
     }
+}
 
-    if !current_arg.is_empty()
-    {
-        args.push(current_arg);
+// Runs a shell function with `args` as its positional parameters.
+fn call_function(shell: &mut Shell, body: &Command, args: &[String]) {
+    let saved = std::mem::replace(&mut shell.positional, args.to_vec());
+    shell.function_depth += 1;
+    execute_compound(shell, body);
+    shell.function_depth -= 1;
+    shell.positional = saved;
+    if shell.flow == Flow::Return {
+        shell.flow = Flow::Normal;
     }
-    return args
 }
 
-fn run_command(shell: &mut Shell, input: &str){
-
-    let commands: Vec<&str> = input.split('|').collect();
+// Expands the targets of a command's redirections.
+fn resolve_redirections(shell: &mut Shell, redirects: &[Redirect]) -> std::result::Result<Redirections, ()> {
+    let mut redirections = Redirections::default();
+    for redirect in redirects {
+        let target = match expand::expand_word(shell, &redirect.target) {
+            Ok(fields) if fields.len() == 1 => fields[0].clone(),
+            Ok(_) => {
+                eprintln!("{}: ambiguous redirect", redirect.target);
+                shell.last_status = 1;
+                return Err(());
+            }
+            Err(e) => {
+                expansion_error(shell, &e);
+                return Err(());
+            }
+        };
 
-    let mut prev_output: Option<std::process::ChildStdout> = None;
+        match redirect.op.as_str() {
+            "<" | "0<" => {
+                redirections.std_in_file = Some(target);
+            }
+            ">>" | "1>>" => {
+                redirections.std_out_file = Some(target);
+                redirections.std_out_append = true;
+            }
+            ">" | "1>" => {
+                redirections.std_out_file = Some(target);
+                redirections.std_out_append = false;
+            }
+            "2>>" => {
+                redirections.std_err_file = Some(target);
+                redirections.std_err_append = true;
+            }
+            "2>" => {
+                redirections.std_err_file = Some(target);
+                redirections.std_err_append = false;
+            }
+            op => {
+                eprintln!("syntax error near unexpected token `{}'", op);
+                shell.last_status = 2;
+                return Err(());
+            }
+        }
+    }
+    Ok(redirections)
+}
 
-    for (ith_command, cmd_string) in commands.iter().enumerate(){
-        let raw_args = arg_parse(&cmd_string.trim());
-        if raw_args.is_empty()
-        {
-            return;
+// Points the shell's own stdout/stderr at the redirection targets while
+// `body` runs, for commands that do not run in a child process.
+fn with_redirections(shell: &mut Shell, redirections: &Redirections, body: impl FnOnce(&mut Shell)) {
+    let mut input = OpenOptions::new();
+    input.read(true);
+    let output = |append: bool| {
+        let mut options = OpenOptions::new();
+        options.write(true).append(append).create(true).truncate(!append);
+        options
+    };
+    let targets = [
+        (0, &redirections.std_in_file, input),
+        (1, &redirections.std_out_file, output(redirections.std_out_append)),
+        (2, &redirections.std_err_file, output(redirections.std_err_append)),
+    ];
+    let mut saved: Vec<(i32, OwnedFd)> = Vec::new();
+    let _ = std::io::stdout().flush();
+
+    for (fd, file_path, options) in targets {
+        let Some(file_path) = file_path else {
+            continue;
+        };
+        match options.open(file_path) {
+            Ok(file) => unsafe {
+                let copy = libc::dup(fd);
+                if copy >= 0 {
+                    saved.push((fd, OwnedFd::from_raw_fd(copy)));
+                    libc::dup2(file.as_raw_fd(), fd);
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: {}", file_path, e);
+                shell.last_status = 1;
+                restore_fds(saved);
+                return;
+            }
         }
+    }
 
-        let mut std_out_r_append = false;
-        let mut std_err_r_append = false;
+    body(shell);
+    restore_fds(saved);
+}
 
-        // detect if there is a redirect option in the command
-        let mut std_out_file: Option<String> = None;
-        let mut std_err_file: Option<String> = None;
-        let mut command_args: Vec<String> = Vec::new();
-        let mut error_in_parsing = false;
+fn restore_fds(saved: Vec<(i32, OwnedFd)>) {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    for (fd, copy) in saved.into_iter().rev() {
+        unsafe {
+            libc::dup2(copy.as_raw_fd(), fd);
+        }
+    }
+}
 
+// Runs `body` in a forked copy of the shell. The child reads `stdin_pipe`
+// and, if `create_pipe` is set, writes into a new pipe whose read end is
+// returned. Otherwise the child is waited for and its status recorded.
+fn run_forked(
+    shell: &mut Shell,
+    stdin_pipe: Option<OwnedFd>,
+    create_pipe: bool,
+    body: impl FnOnce(&mut Shell) -> i32,
+) -> Option<OwnedFd> {
+    let mut fds = [0; 2];
+    if create_pipe && unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        eprintln!("pipe: {}", std::io::Error::last_os_error());
+        shell.last_status = 1;
+        return None;
+    }
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
 
-        let mut i = 0;
-        while i < raw_args.len()
-        {
-            let arg = &raw_args[i];
-    
-            if arg == ">>" || arg == "1>>" || arg == "2>>"
-            {
-                i += 1;
-                if i >= raw_args.len() {
-                    error_in_parsing = true;                   
-                    break;
+    match unsafe { libc::fork() } {
+        -1 => {
+            eprintln!("fork: {}", std::io::Error::last_os_error());
+            shell.last_status = 1;
+            None
+        }
+        0 => {
+            unsafe {
+                if let Some(pipe) = &stdin_pipe {
+                    libc::dup2(pipe.as_raw_fd(), 0);
                 }
-
-                if arg == "2>>" {
-                     std_err_r_append = true;
-                     std_err_file = Some(raw_args[i].clone());
-                } else {
-                     std_out_r_append = true;
-                     std_out_file = Some(raw_args[i].clone());
+                if create_pipe {
+                    libc::dup2(fds[1], 1);
+                    libc::close(fds[0]);
+                    libc::close(fds[1]);
                 }
-        
-                i += 1;
             }
-            else if arg == ">" || arg == "1>" || arg == "2>"
-            {
-                i += 1;
-                if i >= raw_args.len() {
-                    error_in_parsing = true;                   
-                    break;
-                }
-                if arg == "2>" {
-                    std_err_file = Some(raw_args[i].clone());
-                    std_err_r_append = false;
-                } else {
-                    std_out_file = Some(raw_args[i].clone());
-                    std_out_r_append = false;
-                }
-                i += 1;
+            drop(stdin_pipe);
+            shell.interactive = false;
+            shell.pipeline_children.clear();
+            let status = body(shell);
+            let _ = std::io::stdout().flush();
+            let _ = std::io::stderr().flush();
+            unsafe { libc::_exit(status) }
+        }
+        pid => {
+            drop(stdin_pipe);
+            if create_pipe {
+                unsafe { libc::close(fds[1]) };
+                shell.pipeline_children.push(PipelineChild::Forked(pid));
+                Some(unsafe { OwnedFd::from_raw_fd(fds[0]) })
+            } else {
+                shell.last_status = wait_pid(pid);
+                None
             }
-            else
-            {
-                command_args.push(arg.clone());
-                i += 1;
+        }
+    }
+}
+
+fn wait_pid(pid: libc::pid_t) -> i32 {
+    let mut status = 0;
+    unsafe {
+        if libc::waitpid(pid, &mut status, 0) < 0 {
+            return 1;
+        }
+        if libc::WIFSIGNALED(status) {
+            128 + libc::WTERMSIG(status)
+        } else {
+            libc::WEXITSTATUS(status)
+        }
+    }
+}
+
+// Waits for the earlier stages of a pipeline once the last one is done.
+fn wait_pipeline_children(shell: &mut Shell) {
+    for child in shell.pipeline_children.drain(..) {
+        match child {
+            PipelineChild::Process(mut child) => {
+                let _ = child.wait();
+            }
+            PipelineChild::Forked(pid) => {
+                wait_pid(pid);
             }
         }
+    }
+}
 
-        if error_in_parsing || command_args.is_empty()
-        {
-            println!("error in parsing amk");
-            return;
+// Expands and runs one simple command of a pipeline. Returns the read end
+// of its output pipe if it is not the last stage, or Err if the pipeline
+// cannot go on.
+fn run_simple_command(
+    shell: &mut Shell,
+    words: &[String],
+    redirects: &[Redirect],
+    stdin_pipe: Option<OwnedFd>,
+    is_last: bool,
+) -> std::result::Result<Option<OwnedFd>, ()> {
+
+    // detect if there is a redirect option in the command
+    let redirections = resolve_redirections(shell, redirects)?;
+
+    // Leading NAME=value words are assignments rather than arguments.
+    let assignment_count = words
+        .iter()
+        .take_while(|word| variables::parse_assignment(word).is_some())
+        .count();
+    let (assignment_words, words) = words.split_at(assignment_count);
+
+    let command_args = match expand_command_words(shell, words) {
+        Ok(args) => args,
+        Err(e) => {
+            expansion_error(shell, &e);
+            return Err(());
         }
+    };
 
-        let is_last = ith_command == commands.len() - 1;
-        
+    if command_args.is_empty()
+    {
+        shell.last_status = 0;
+        for word in assignment_words {
+            if let Err(e) = expand::assign(shell, word) {
+                expansion_error(shell, &e);
+                return Err(());
+            }
+        }
+        return Ok(None);
+    }
 
-        let new_prev_output = run_single_command(
-            shell,
-            &command_args,
-            prev_output.take(),
-            std_out_file.clone(),
-            std_out_r_append,
-            std_err_file.clone(),
-            std_err_r_append,
-            is_last,
-        );
-        
-        prev_output = new_prev_output;
+    // With a command, the assignments only go into that command's environment.
+    let mut assignments: Vec<(String, String)> = Vec::new();
+    for word in assignment_words {
+        let assignment = variables::parse_assignment(word).unwrap();
+        match expand::expand_string(shell, assignment.value) {
+            Ok(value) => assignments.push((assignment.name.to_string(), value)),
+            Err(e) => {
+                expansion_error(shell, &e);
+                return Err(());
+            }
+        }
+    }
+
+    Ok(run_single_command(
+        shell,
+        &command_args,
+        &assignments,
+        stdin_pipe,
+        &redirections,
+        is_last,
+        true,
+    ))
+}
+
+// Reports an expansion that failed, such as `${name:?message}` on an unset
+// name. A shell that is not interactive exits rather than running the rest
+// of its commands.
+fn expansion_error(shell: &mut Shell, message: &str) {
+    eprintln!("{}", message);
+    shell.last_status = 1;
+    if !shell.interactive {
+        shell.flow = Flow::Exit;
     }
 }
 
+// Expands the words of a simple command. Assignment-like arguments of a
+// declaration builtin are passed on unexpanded so the builtin can assign them.
+fn expand_command_words(shell: &mut Shell, words: &[String]) -> std::result::Result<Vec<String>, String> {
+    let Some(first) = words.first() else {
+        return Ok(Vec::new());
+    };
+    let mut args = expand::expand_word(shell, first)?;
+    let is_declaration = args.first().is_some_and(|cmd| DECLARATION_BUILTINS.contains(&cmd.as_str()));
+    for word in &words[1..] {
+        if is_declaration && variables::parse_assignment(word).is_some() {
+            args.push(word.clone());
+        } else {
+            args.extend(expand::expand_word(shell, word)?);
+        }
+    }
+    Ok(args)
+}
 
 fn run_single_command(
     shell: &mut Shell,
     command_args: &[String],
-    stdin_pipe: Option<std::process::ChildStdout>, // The stdin for this command
-    std_out_file: Option<String>,
-    std_out_r_append: bool,
-    std_err_file: Option<String>,
-    std_err_r_append: bool,
+    assignments: &[(String, String)], // NAME=value words placed in the command's environment
+    stdin_pipe: Option<OwnedFd>, // The stdin for this command
+    redirections: &Redirections,
     is_last: bool, // True if this is the last command in the pipeline
-) -> Option<std::process::ChildStdout>{
+    use_functions: bool, // False for `command name`, which skips functions
+) -> Option<OwnedFd>{
 
     let command = command_args[0].as_str();
     // Map the rest of the arguments from &String to &str and collect them
     let parts: Vec<&str> = command_args[1..].iter().map(|s| s.as_str()).collect();
+
+    // `command [-p] name [args]` runs a builtin or program even if a
+    // function has the same name. With -v or -V it describes the name.
+    if command == "command" {
+        let skip = if parts.first() == Some(&"-p") { 2 } else { 1 };
+        let describe = parts.first().is_some_and(|arg| arg.starts_with('-') && *arg != "-p" && *arg != "--");
+        if !describe && command_args.len() > skip {
+            let skip = if command_args.get(skip).map(|s| s.as_str()) == Some("--") { skip + 1 } else { skip };
+            if command_args.len() > skip {
+                return run_single_command(shell, &command_args[skip..], assignments, stdin_pipe, redirections, is_last, false);
+            }
+        }
+    }
+
+    // Assignments before a builtin or function last only while it runs.
+    let function = shell.functions.get(command).filter(|_| use_functions).cloned();
+    if !assignments.is_empty() && (BUILTINS.contains(&command) || function.is_some()) {
+        let saved: Vec<_> = assignments
+            .iter()
+            .map(|(name, _)| (name.clone(), shell.vars.get_var(name).cloned()))
+            .collect();
+        for (name, value) in assignments {
+            shell.vars.set(name, value);
+        }
+        let output = run_single_command(shell, command_args, &[], stdin_pipe, redirections, is_last, use_functions);
+        for (name, var) in saved.into_iter().rev() {
+            shell.vars.restore(&name, var);
+        }
+        return output;
+    }
+
+    // Inside a pipeline a builtin runs in a child like any other stage, so
+    // that it can read the previous stage's output and feed the next one.
+    if BUILTINS.contains(&command) && (stdin_pipe.is_some() || !is_last) {
+        return run_forked(shell, stdin_pipe, !is_last, |shell| {
+            run_single_command(shell, command_args, assignments, None, redirections, true, use_functions);
+            shell.last_status
+        });
+    }
+
+    if let Some(body) = function {
+        let args = &command_args[1..];
+        if stdin_pipe.is_some() || !is_last {
+            return run_forked(shell, stdin_pipe, !is_last, |shell| {
+                with_redirections(shell, redirections, |shell| call_function(shell, &body, args));
+                shell.last_status
+            });
+        }
+        with_redirections(shell, redirections, |shell| call_function(shell, &body, args));
+        return None;
+    }
+
     match command
     {
-        "echo" | "pwd" | "type" | "history" => {
+        "echo" | "pwd" | "cd" | "type" | "history" | "declare" | "unset" | "shift" | "set" | "getopts"
+            | "test" | "[" | "printf" | "shopt"
+            | "pushd" | "popd" | "dirs" | "command" | "which" | "hash" | "histquery" => {
             let mut std_out_s = String::new();
             let mut std_err_s = String::new();
-            match command {
+            let status = match command {
                 "echo" =>
                 {
-                    std_out_s = format!("{}\n", parts.join(" "));
-                    std_err_s = "".to_string();
+                    builtins::echo(shell, &parts, &mut std_out_s)
+                }
+                "shopt" =>
+                {
+                    builtins::shopt(shell, &parts, &mut std_out_s, &mut std_err_s)
                 }
                 "pwd" =>
                 {
-                    if let Ok(current_dir) = env::current_dir()
-                    {
-                        std_out_s = format!("{}\n", current_dir.to_str().unwrap());
-                        std_err_s = "".to_string();
-                    }
-                    else
-                    {
-                        std_err_s = "Failed to get current directory\n".to_string();
-                        std_out_s = "".to_string();
-                    }
+                    builtins::pwd(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "cd" =>
+                {
+                    builtins::cd(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "pushd" =>
+                {
+                    builtins::pushd(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "popd" =>
+                {
+                    builtins::popd(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "dirs" =>
+                {
+                    builtins::dirs(shell, &parts, &mut std_out_s, &mut std_err_s)
                 }
                 "type" =>
                 {
-                    if let Some(arg) = parts.get(0)
-                    {
-                        if  matches!(*arg, "echo" | "exit" | "type" | "pwd" | "cd" | "history")
-                        {
-                            std_out_s = format!("{} is a shell builtin\n", arg)
-                        }
-                        else if let Some(path) = find_executable_in_path(arg)
-                        {
-                            std_out_s = format!("{} is {}\n", arg, path.display())
-                        }
-                        else
-                        {
-                            std_err_s = format!("{} not found\n", arg)
-                        };
-                    };
+                    builtins::type_builtin(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "command" =>
+                {
+                    builtins::command(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "hash" =>
+                {
+                    builtins::hash(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "which" =>
+                {
+                    builtins::which(shell, &parts, &mut std_out_s, &mut std_err_s)
                 }
                 "history" =>
                 {
-                    let mut limit = 15;
-
-                    if let Some(arg) = parts.get(0)
-                    { 
-                        if arg == &"-r"
-                        {
-                            if let Some(file_path_str) = parts.get(1) {
-                                let file_path = PathBuf::from(file_path_str);
-                                let _ = shell.editor.load_history(&file_path);
-                                return None;
-                            } else {
-                                std_err_s = "history: option requires an argument -- 'r'\nhistory: usage: history [-r] [filename]\n".to_string();
-                            }
-                        }
-                        else if arg == &"-w"
-                        {
-                            if let Some(file_path_str) = parts.get(1) {
-                                let file_path = PathBuf::from(file_path_str);
-                                let _ = shell.save_history(&file_path);
-                                return None;
-                            } else {
-                                std_err_s = "history: option requires an argument -- 'r'\nhistory: usage: history [-r] [filename]\n".to_string();
-                            }
-                        }
-                        else if arg == &"-a"
-                        {
-                            if let Some(file_path_str) = parts.get(1) {
-                                let file_path = PathBuf::from(file_path_str);
-                                let _ = shell.append_history(&file_path);
-                                return None;
-                            }
-                            return None;
-                        }
-                        else {
-                            if let Ok(num) = arg.parse::<usize>()
-                            {
-                                limit = num;
-                            }
-                            else {
-                                std_err_s = format!("history: invalid argument '{}'\n", arg);
-                            }  
-                        }
-                    }
-
-                    let history = shell.editor.history();
-                    if !history.is_empty()
-                    {
-                        let start_index = history.len().saturating_sub(limit);
-                        std_out_s = history.iter()
-                                    .skip(start_index)
-                                    .enumerate()
-                                    .map(|(i, entry)| format!("{} {}", start_index + i , entry))
-                                    .collect::<Vec<String>>()
-                                    .join("\n");
-                        std_out_s.push('\n');
-                    }
+                    builtins::history(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "histquery" =>
+                {
+                    builtins::histquery(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "declare" =>
+                {
+                    builtins::declare(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "unset" =>
+                {
+                    builtins::unset(shell, &parts, &mut std_err_s)
+                }
+                "shift" =>
+                {
+                    builtins::shift(shell, &parts, &mut std_err_s)
+                }
+                "set" =>
+                {
+                    builtins::set(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "getopts" =>
+                {
+                    builtins::getopts(shell, &parts, &mut std_err_s)
+                }
+                "printf" =>
+                {
+                    builtins::printf(shell, &parts, &mut std_out_s, &mut std_err_s)
+                }
+                "test" | "[" =>
+                {
+                    builtins::test(shell, command, &parts, &mut std_err_s)
                 }
                 _ => {
                     return None;
                 }
+            };
+            shell.last_status = status;
+            handle_built_in_output(&std_out_s, &std_err_s, redirections);
+            None
+        }
+        // fc runs commands itself, so its redirections apply to those too.
+        "fc" =>
+        {
+            with_redirections(shell, redirections, |shell| {
+                let mut std_out_s = String::new();
+                let mut std_err_s = String::new();
+                let status = builtins::fc(shell, &parts, &mut std_out_s, &mut std_err_s);
+                shell.last_status = status;
+                print!("{}", std_out_s);
+                eprint!("{}", std_err_s);
+                let _ = std::io::stdout().flush();
+            });
+            None
+        }
+        "read" | "mapfile" | "readarray" =>
+        {
+            with_redirections(shell, redirections, |shell| {
+                let mut std_err_s = String::new();
+                shell.last_status = if command == "read" {
+                    builtins::read(shell, &parts, &mut std_err_s)
+                } else {
+                    builtins::mapfile(shell, command, &parts, &mut std_err_s)
+                };
+                eprint!("{}", std_err_s);
+            });
+            None
+        }
+        "return" =>
+        {
+            if shell.function_depth == 0 {
+                eprintln!("return: can only `return' from a function or sourced script");
+                shell.last_status = 1;
+                return None;
             }
-            if is_last
-            {
-                handle_built_in_output(&std_out_s, std_out_file, std_out_r_append,&std_err_s, std_err_file, std_err_r_append);
-                None
-            }
-            else {
-                return execute_piped("printf", 
-                &["%s", &std_out_s], 
-                stdin_pipe, 
-                None, 
-                false,
-                None, 
-                false, 
-                true)
+            match parts.first().map(|arg| arg.parse::<i32>()) {
+                Some(Ok(code)) => shell.last_status = code & 0xff,
+                Some(Err(_)) => {
+                    eprintln!("return: {}: numeric argument required", parts[0]);
+                    shell.last_status = 2;
+                }
+                None => {}
             }
-            
+            shell.flow = Flow::Return;
+            None
         }
-        "exit" |"cd" =>
+        "exit" =>
         {
-            if stdin_pipe.is_none()
+            let _ =shell.save_history_default();
+            if let Some(arg) = parts.first()
             {
-                
-                match command{
-                    "cd" =>
-                    {
-                        if let Some(arg) = parts.get(0)
-                        {
-                            change_directory(arg);
-                        }
-                    }
-                    "exit" =>
-                    {
-                        let _ =shell.save_history_default();
-                        if let Some(arg) = parts.get(0)
-                        {
-                            if let Ok(exit_code) = arg.parse::<i32>()
-                            {
-                                std::process::exit(exit_code);
-                            }
-                            else
-                            {
-                                std::process::exit(1);
-                            }
-                        }
-                        else
-                        {
-                            std::process::exit(0);
-                        }
-                    }
-                    _ => {
-                        return None;
-                    }
-                }   
+                if let Ok(exit_code) = arg.parse::<i32>()
+                {
+                    std::process::exit(exit_code);
+                }
+                else
+                {
+                    std::process::exit(1);
+                }
+            }
+            else
+            {
+                std::process::exit(0);
             }
-            None
         }
         _ =>
         {
             execute_piped(
+                shell,
                 command, 
                 &parts, 
+                assignments,
                 stdin_pipe, 
-                std_out_file, 
-                std_out_r_append, 
-                std_err_file, 
-                std_err_r_append,
+                redirections,
                 !is_last, // Pipe the output if it's NOT the last command
             )
         }
@@ -684,62 +1897,77 @@ fn run_single_command(
 }
 
 fn execute_piped(
+    shell: &mut Shell,
     command: &str, 
     args: &[&str], 
-    mut stdin_pipe: Option<std::process::ChildStdout>, // Input from previous pipe
-    std_out: Option<String>, 
-    std_out_append: bool, 
-    std_err: Option<String>, 
-    std_err_append: bool,
+    assignments: &[(String, String)],
+    mut stdin_pipe: Option<OwnedFd>, // Input from previous pipe
+    redirections: &Redirections,
     create_pipe: bool,
-) -> Option<std::process::ChildStdout>
+) -> Option<OwnedFd>
 {
-    if find_executable_in_path(command).is_none() {
+    let Some(path) = shell.find_command(command) else {
         println!("{}: command not found", command);
+        shell.last_status = 127;
         return None;
-    }
+    };
     
-    let mut process_command = std::process::Command::new(command);
+    let mut process_command = std::process::Command::new(path);
+    process_command.arg0(command);
     process_command.args(args);
+    process_command.envs(assignments.iter().map(|(name, value)| (name, value)));
     
     if let Some(pipe) = stdin_pipe.take() {
         process_command.stdin(pipe);
+    } else if let Some(input_file) = &redirections.std_in_file {
+        match std::fs::File::open(input_file) {
+            Ok(file) => {
+                process_command.stdin(file);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", input_file, e);
+                shell.last_status = 1;
+                return None;
+            }
+        }
     }
 
     let mut pipe_output = None;
     if create_pipe {
         process_command.stdout(std::process::Stdio::piped());
-    } else if let Some(output_file) = std_out {
+    } else if let Some(output_file) = &redirections.std_out_file {
         match std::fs::OpenOptions::new()
             .write(true)
-            .append(std_out_append)
+            .append(redirections.std_out_append)
             .create(true)
-            .truncate(!std_out_append)
-            .open(&output_file)
+            .truncate(!redirections.std_out_append)
+            .open(output_file)
             {
                 Ok(file) => {
                     process_command.stdout(file);
                 }
                 Err(e) => {
                     eprintln!("Failed to open error file: {}", e);
+                    shell.last_status = 1;
                     return None;
                 }
             }
     }
 
-    if let Some(error_file) = std_err {
+    if let Some(error_file) = &redirections.std_err_file {
         match std::fs::OpenOptions::new()
             .write(true)
-            .append(std_err_append)
+            .append(redirections.std_err_append)
             .create(true)
-            .truncate(!std_err_append)
-            .open(&error_file)
+            .truncate(!redirections.std_err_append)
+            .open(error_file)
             {
                 Ok(file) => {
                     process_command.stderr(file);
                 }
                 Err(e) => {
                     eprintln!("Failed to open error file: {}", e);
+                    shell.last_status = 1;
                     return None;
                 }
             }
@@ -748,35 +1976,75 @@ fn execute_piped(
     // --- Spawn and Return Output Pipe ---
     match process_command.spawn() {
         Ok(mut child) => {
-            // If output was piped, take and return the ChildStdout handle
+            // If output was piped, hand the read end to the next command and
+            // wait for this one once the whole pipeline has been started.
             if create_pipe {
-                pipe_output = child.stdout.take();
+                pipe_output = child.stdout.take().map(OwnedFd::from);
+                shell.pipeline_children.push(PipelineChild::Process(child));
+                return pipe_output;
             }
-            
-            // IMPORTANT: If this is the final command (create_pipe=false),
-            // you must wait for it to finish. If it's not the final command,
-            // the subsequent `spawn` will implicitly wait via the pipe.
-            if !create_pipe && stdin_pipe.is_none() {
-                // If it's a standalone command, wait for it
-                match child.wait() {
-                    Ok(_) => {},
-                    Err(e) => eprintln!("Execution error: {}", e),
-                }
+
+            match child.wait() {
+                Ok(status) => {
+                    shell.last_status = exit_code(status);
+                },
+                Err(e) => eprintln!("Execution error: {}", e),
             }
-            
             pipe_output
         }
         Err(e) => {
             eprintln!("Failed to execute {}: {}", command, e);
+            shell.last_status = 126;
             None
         }
     }
 }
 
+// Converts a child's exit status into the shell's `$?` value, using
+// 128 + signal number for processes killed by a signal.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
 
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> 
 {
     let mut shell = Shell::new();
-    shell.run()?;
-    Ok(())
+    let mut args = env::args().skip(1);
+
+    // `shell -c command [name [args...]]` and `shell script [args...]` run
+    // non-interactively and exit with the status of the last command.
+    match args.next() {
+        Some(flag) if flag == "-c" => {
+            let Some(command) = args.next() else {
+                eprintln!("-c: option requires an argument");
+                std::process::exit(2);
+            };
+            if let Some(name) = args.next() {
+                shell.script_name = name;
+            }
+            shell.positional = args.collect();
+            shell.interactive = false;
+            run_command(&mut shell, &command);
+        }
+        Some(path) => {
+            let script = match std::fs::read_to_string(&path) {
+                Ok(script) => script,
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    std::process::exit(127);
+                }
+            };
+            shell.script_name = path;
+            shell.positional = args.collect();
+            shell.interactive = false;
+            run_command(&mut shell, &script);
+        }
+        None => {
+            shell.run()?;
+            return Ok(());
+        }
+    }
+    std::process::exit(shell.last_status);
 }
\ No newline at end of file
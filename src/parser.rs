@@ -0,0 +1,484 @@
+use std::rc::Rc;
+
+use crate::lexer::{self, Token};
+use crate::variables::is_valid_name;
+
+// Builds a syntax tree out of the lexer's tokens. Words stay raw; they are
+// expanded only when the command that contains them runs.
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub op: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    // Words (assignments included) and redirections in command-line order.
+    Simple { words: Vec<String>, redirects: Vec<Redirect> },
+    // `{ list; }`, run in the current shell.
+    Group { body: List, redirects: Vec<Redirect> },
+    // `( list )`, run in a child process.
+    Subshell { body: List, redirects: Vec<Redirect> },
+    // `[[ expression ]]`: the raw words and operators between the brackets.
+    Conditional { words: Vec<String>, redirects: Vec<Redirect> },
+    // `name() compound-command` or `function name compound-command`.
+    FunctionDef { name: String, body: Rc<Command> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub negated: bool,
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+pub type List = Vec<AndOr>;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    // The input ended before the command did, so more lines could
+    // complete it.
+    pub incomplete: bool,
+}
+
+impl ParseError {
+    fn unexpected(token: Option<&Token>) -> Self {
+        match token {
+            None => ParseError {
+                message: "syntax error: unexpected end of file".to_string(),
+                incomplete: true,
+            },
+            Some(token) => {
+                let text = match token {
+                    Token::Word(word) => word.as_str(),
+                    Token::Operator(op) if op == "\n" => "newline",
+                    Token::Operator(op) => op.as_str(),
+                };
+                ParseError {
+                    message: format!("syntax error near unexpected token `{}'", text),
+                    incomplete: false,
+                }
+            }
+        }
+    }
+}
+
+// Words that start or end a compound command when they appear where a
+// command name is expected.
+const RESERVED_WORDS: &[&str] = &["{", "}", "!", "function", "[[", "]]"];
+
+pub fn is_reserved_word(word: &str) -> bool {
+    RESERVED_WORDS.contains(&word)
+}
+
+pub fn parse(input: &str) -> Result<List, ParseError> {
+    // The lexer only fails on unterminated quotes and expansions.
+    let tokens = lexer::tokenize(input).map_err(|message| ParseError { message, incomplete: true })?;
+    let mut parser = Parser { tokens, pos: 0 };
+    parser.skip_newlines();
+    let list = parser.list(&[])?;
+    if let Some(token) = parser.peek() {
+        return Err(ParseError::unexpected(Some(token)));
+    }
+    Ok(list)
+}
+
+// True if the input needs more lines: it has an unclosed quote, group or
+// conditional, ends in an operator such as `|` or `&&`, ends in a
+// backslash that continues the line, has a `<<WORD` here-document whose
+// WORD line has not come yet, or has an `if`, `for`, `while`, `until` or
+// `case` without its `fi`, `done` or `esac`. Those are only counted here,
+// since the grammar does not run them yet.
+pub fn is_incomplete(input: &str) -> bool {
+    let trailing_backslashes = input.chars().rev().take_while(|c| *c == '\\').count();
+    if trailing_backslashes % 2 == 1 {
+        return true;
+    }
+    if let Some(open) = open_here_documents(input) {
+        return open;
+    }
+    match parse(input) {
+        Err(ParseError { incomplete: true, .. }) => true,
+        _ => lexer::tokenize(input).is_ok_and(|tokens| has_open_keywords(&tokens)),
+    }
+}
+
+// Reserved words that open a compound command, with the word that closes it.
+const KEYWORD_PAIRS: &[(&str, &str)] = &[("if", "fi"), ("for", "done"), ("while", "done"), ("until", "done"), ("case", "esac")];
+
+// Reserved words after which the next word is a command name again.
+const COMMAND_PREFIXES: &[&str] = &["if", "then", "else", "elif", "while", "until", "do", "{", "!"];
+
+// Whether an `if`, loop or `case` is still waiting for its closing word.
+// Reserved words only count where a command name could be, so that
+// `echo if` opens nothing.
+fn has_open_keywords(tokens: &[Token]) -> bool {
+    let mut open: Vec<&str> = Vec::new();
+    let mut command_position = true;
+    for token in tokens {
+        command_position = match token {
+            Token::Operator(op) => !Parser::is_redirect_op(op),
+            Token::Word(word) if command_position => {
+                if let Some((_, close)) = KEYWORD_PAIRS.iter().find(|(opener, _)| opener == word) {
+                    open.push(close);
+                } else if open.last() == Some(&word.as_str()) {
+                    open.pop();
+                }
+                COMMAND_PREFIXES.contains(&word.as_str())
+            }
+            Token::Word(_) => false,
+        };
+    }
+    !open.is_empty()
+}
+
+// Follows the here-documents of `input` line by line: the lines after one
+// that starts them are their bodies, up to each delimiter in turn. Returns
+// None if there are none, or whether any is still waiting for its delimiter.
+fn open_here_documents(input: &str) -> Option<bool> {
+    let mut pending: std::collections::VecDeque<(String, bool)> = std::collections::VecDeque::new();
+    let mut found = false;
+    for line in input.split('\n') {
+        if let Some((delimiter, strip_tabs)) = pending.front() {
+            let body = if *strip_tabs { line.trim_start_matches('\t') } else { line };
+            if body == delimiter {
+                pending.pop_front();
+            }
+            continue;
+        }
+        let Ok(tokens) = lexer::tokenize(line) else {
+            continue;
+        };
+        for pair in tokens.windows(2) {
+            if let [Token::Operator(op), Token::Word(word)] = pair {
+                let op = op.trim_start_matches(|c: char| c.is_ascii_digit());
+                if op == "<<" || op == "<<-" {
+                    found = true;
+                    let delimiter: String = word.chars().filter(|c| !matches!(c, '\'' | '"' | '\\')).collect();
+                    pending.push_back((delimiter, op == "<<-"));
+                }
+            }
+        }
+    }
+    found.then_some(!pending.is_empty())
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek_op() == Some("\n") {
+            self.pos += 1;
+        }
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.peek_word() == Some(word) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(ParseError::unexpected(self.peek()))
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), ParseError> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(ParseError::unexpected(self.peek()))
+        }
+    }
+
+    // True if the next token ends the current list: end of input, a `)`,
+    // or one of the given reserved words.
+    fn at_list_end(&self, terminators: &[&str]) -> bool {
+        match self.peek() {
+            None => true,
+            Some(Token::Operator(op)) => op == ")",
+            Some(Token::Word(word)) => terminators.contains(&word.as_str()),
+        }
+    }
+
+    // A sequence of and-or lists separated by `;` or newlines.
+    fn list(&mut self, terminators: &[&str]) -> Result<List, ParseError> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_list_end(terminators) {
+                break;
+            }
+            list.push(self.and_or()?);
+            match self.peek_op() {
+                Some(";") | Some("\n") => self.pos += 1,
+                _ => break,
+            }
+        }
+        Ok(list)
+    }
+
+    fn and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek_op() {
+                Some("&&") => Connector::And,
+                Some("||") => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+        Ok(AndOr { first, rest })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut negated = false;
+        if self.peek_word() == Some("!") {
+            negated = true;
+            self.pos += 1;
+        }
+        let mut commands = vec![self.command()?];
+        while self.peek_op() == Some("|") {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline { negated, commands })
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        match self.peek() {
+            Some(Token::Word(word)) if word == "{" => {
+                let body = self.group()?;
+                let redirects = self.redirects()?;
+                Ok(Command::Group { body, redirects })
+            }
+            Some(Token::Word(word)) if word == "[[" => {
+                let words = self.conditional()?;
+                let redirects = self.redirects()?;
+                Ok(Command::Conditional { words, redirects })
+            }
+            Some(Token::Word(word)) if word == "function" => {
+                self.pos += 1;
+                let Some(name) = self.peek_word().map(|w| w.to_string()) else {
+                    return Err(ParseError::unexpected(self.peek()));
+                };
+                self.pos += 1;
+                if self.peek_op() == Some("(") {
+                    self.pos += 1;
+                    self.expect_op(")")?;
+                }
+                self.function_body(name)
+            }
+            Some(Token::Word(word))
+                if is_valid_name(word)
+                    && self.tokens.get(self.pos + 1) == Some(&Token::Operator("(".to_string())) =>
+            {
+                let name = word.clone();
+                self.pos += 2;
+                self.expect_op(")")?;
+                self.function_body(name)
+            }
+            Some(Token::Operator(op)) if op == "(" => {
+                self.pos += 1;
+                let body = self.list(&[])?;
+                self.expect_op(")")?;
+                let redirects = self.redirects()?;
+                Ok(Command::Subshell { body, redirects })
+            }
+            Some(Token::Word(word)) if word == "}" || word == "]]" => Err(ParseError::unexpected(self.peek())),
+            Some(_) => self.simple_command(),
+            None => Err(ParseError::unexpected(None)),
+        }
+    }
+
+    fn group(&mut self) -> Result<List, ParseError> {
+        self.expect_word("{")?;
+        let body = self.list(&["}"])?;
+        if body.is_empty() {
+            return Err(ParseError::unexpected(self.peek()));
+        }
+        if self.peek().is_none() {
+            return Err(ParseError::unexpected(None));
+        }
+        self.expect_word("}")?;
+        Ok(body)
+    }
+
+    fn conditional(&mut self) -> Result<Vec<String>, ParseError> {
+        self.expect_word("[[")?;
+        let mut words = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Word(word)) if word == "]]" => break,
+                Some(Token::Word(word)) => words.push(word.clone()),
+                Some(Token::Operator(op)) if op == "\n" => {}
+                Some(Token::Operator(op)) => words.push(op.clone()),
+                None => return Err(ParseError::unexpected(None)),
+            }
+            self.pos += 1;
+        }
+        if words.is_empty() {
+            return Err(ParseError::unexpected(self.peek()));
+        }
+        self.pos += 1;
+        Ok(words)
+    }
+
+    fn function_body(&mut self, name: String) -> Result<Command, ParseError> {
+        self.skip_newlines();
+        let body = match self.peek() {
+            Some(Token::Word(word)) if word == "{" => self.command()?,
+            Some(Token::Operator(op)) if op == "(" => self.command()?,
+            other => return Err(ParseError::unexpected(other)),
+        };
+        Ok(Command::FunctionDef { name, body: Rc::new(body) })
+    }
+
+    fn redirect(&mut self) -> Result<Redirect, ParseError> {
+        let Some(op) = self.peek_op().map(|op| op.to_string()) else {
+            return Err(ParseError::unexpected(self.peek()));
+        };
+        self.pos += 1;
+        match self.peek() {
+            Some(Token::Word(_)) if op.ends_with("<<") || op.ends_with("<<-") => Err(ParseError {
+                message: format!("{}: here-documents are not supported", op),
+                incomplete: false,
+            }),
+            Some(Token::Word(target)) => {
+                let target = target.clone();
+                self.pos += 1;
+                Ok(Redirect { op, target })
+            }
+            Some(token) => Err(ParseError::unexpected(Some(token))),
+            None => Err(ParseError::unexpected(Some(&Token::Operator("\n".to_string())))),
+        }
+    }
+
+    fn is_redirect_op(op: &str) -> bool {
+        op.trim_start_matches(|c: char| c.is_ascii_digit()).starts_with(['<', '>'])
+    }
+
+    fn redirects(&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+        while self.peek_op().is_some_and(Self::is_redirect_op) {
+            redirects.push(self.redirect()?);
+        }
+        Ok(redirects)
+    }
+
+    fn simple_command(&mut self) -> Result<Command, ParseError> {
+        let mut words = Vec::new();
+        let mut redirects = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Word(word)) => {
+                    words.push(word.clone());
+                    self.pos += 1;
+                }
+                Some(Token::Operator(op)) if Self::is_redirect_op(op) => {
+                    redirects.push(self.redirect()?);
+                }
+                _ => break,
+            }
+        }
+        if words.is_empty() && redirects.is_empty() {
+            return Err(ParseError::unexpected(self.peek()));
+        }
+        Ok(Command::Simple { words, redirects })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn here_document_waits_for_its_delimiter() {
+        assert!(is_incomplete("cat <<EOF"));
+        assert!(is_incomplete("cat <<EOF\nhello"));
+        assert!(!is_incomplete("cat <<EOF\nhello\nEOF"));
+        assert!(is_incomplete("cat <<-'END'\n\thello"));
+        assert!(!is_incomplete("cat <<-'END'\n\thello\n\tEND"));
+        assert!(is_incomplete("cat <<A <<B\na\nA\nb"));
+        assert!(!is_incomplete("cat <<A <<B\na\nA\nb\nB"));
+    }
+
+    #[test]
+    fn unfinished_lines_ask_for_more() {
+        assert!(is_incomplete("echo 'open"));
+        assert!(is_incomplete("ls |"));
+        assert!(is_incomplete("{ echo a"));
+        assert!(is_incomplete("echo a \\"));
+        assert!(!is_incomplete("echo a \\\\"));
+        assert!(!is_incomplete("echo done"));
+        assert!(!is_incomplete("echo )"));
+    }
+
+    #[test]
+    fn open_compound_commands_wait_for_their_closing_word() {
+        assert!(is_incomplete("if true; then"));
+        assert!(is_incomplete("if true; then\n  echo a"));
+        assert!(!is_incomplete("if true; then echo a; fi"));
+        assert!(is_incomplete("for x in a b\ndo\n  echo $x"));
+        assert!(!is_incomplete("for x in a b; do echo $x; done"));
+        assert!(is_incomplete("while true; do if false; then :; fi"));
+        assert!(!is_incomplete("while true; do if false; then :; fi; done"));
+        assert!(is_incomplete("until false; do :"));
+        assert!(is_incomplete("case $x in\n a) echo a;;"));
+        assert!(!is_incomplete("case $x in\n a) echo a;;\nesac"));
+    }
+
+    #[test]
+    fn reserved_words_count_only_as_command_names() {
+        assert!(!is_incomplete("echo if for while"));
+        assert!(!is_incomplete("echo done > if"));
+        assert!(!is_incomplete("ls # if"));
+        assert!(!is_incomplete("echo 'if'"));
+        assert!(is_incomplete("true && if true; then"));
+        assert!(is_incomplete("! while true; do"));
+    }
+
+    #[test]
+    fn here_document_is_reported_as_unsupported() {
+        let error = parse("cat <<EOF\nhi\nEOF").unwrap_err();
+        assert_eq!(error.message, "<<: here-documents are not supported");
+        assert!(!error.incomplete);
+    }
+}
@@ -0,0 +1,510 @@
+// The formatting engine behind the `printf` builtin, plus the backslash
+// escapes shared with `%b`.
+
+// The result of expanding backslash escapes. `stop` is set when a `\c`
+// asked for all further output to be dropped.
+pub struct Escaped {
+    pub text: String,
+    pub stop: bool,
+}
+
+fn push_code(text: &mut String, code: u32) {
+    text.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+}
+
+// Reads up to `max` digits of the given radix starting at `start`. A value
+// too large for a u32 stops at u32::MAX.
+fn read_digits(chars: &[char], start: usize, max: usize, radix: u32) -> (u32, usize) {
+    let mut value: u32 = 0;
+    let mut len = 0;
+    while len < max {
+        match chars.get(start + len).and_then(|c| c.to_digit(radix)) {
+            Some(digit) => value = value.saturating_mul(radix).saturating_add(digit),
+            None => break,
+        }
+        len += 1;
+    }
+    (value, len)
+}
+
+// Expands backslash escapes. In a printf format, octal escapes are `\NNN`;
+// for `%b` (and `echo -e`) they are `\0NNN` and `\c` ends the output.
+pub fn expand_escapes(text: &str, in_format: bool) -> Escaped {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let c = chars[i + 1];
+        i += 2;
+        match c {
+            'a' => out.push('\x07'),
+            'b' => out.push('\x08'),
+            'e' | 'E' => out.push('\x1b'),
+            'f' => out.push('\x0c'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'v' => out.push('\x0b'),
+            '\\' => out.push('\\'),
+            '"' if in_format => out.push('"'),
+            '\'' if in_format => out.push('\''),
+            '?' if in_format => out.push('?'),
+            'c' if !in_format => return Escaped { text: out, stop: true },
+            '0' if !in_format => {
+                let (value, len) = read_digits(&chars, i, 3, 8);
+                push_code(&mut out, value);
+                i += len;
+            }
+            '0'..='7' if in_format => {
+                let (value, len) = read_digits(&chars, i - 1, 3, 8);
+                push_code(&mut out, value);
+                i += len - 1;
+            }
+            'x' | 'u' | 'U' => {
+                let max = match c {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                let (value, len) = read_digits(&chars, i, max, 16);
+                if len == 0 {
+                    out.push('\\');
+                    out.push(c);
+                } else {
+                    push_code(&mut out, value);
+                    i += len;
+                }
+            }
+            _ => {
+                out.push('\\');
+                out.push(c);
+            }
+        }
+    }
+    Escaped { text: out, stop: false }
+}
+
+// Quotes a string so that the shell reads it back as the same word, for `%q`.
+pub fn shell_quote(text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+    if text.chars().any(|c| c.is_control()) {
+        let mut quoted = String::from("$'");
+        for c in text.chars() {
+            match c {
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                '\x1b' => quoted.push_str("\\E"),
+                '\'' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                c if c.is_control() => quoted.push_str(&format!("\\{:03o}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('\'');
+        return quoted;
+    }
+    let mut quoted = String::new();
+    for (i, c) in text.chars().enumerate() {
+        if " \t!\"#$&'()*,;<=>?[\\]^`{|}".contains(c) || (c == '~' && i == 0) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    // Pads `prefix` + `body` to the field width. Zero padding goes between
+    // the sign or base prefix and the digits.
+    fn pad(&self, prefix: &str, body: &str, zero_allowed: bool) -> String {
+        let len = prefix.chars().count() + body.chars().count();
+        if len >= self.width {
+            return format!("{}{}", prefix, body);
+        }
+        let fill = self.width - len;
+        if self.left {
+            format!("{}{}{}", prefix, body, " ".repeat(fill))
+        } else if self.zero && zero_allowed {
+            format!("{}{}{}", prefix, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), prefix, body)
+        }
+    }
+
+    fn pad_text(&self, text: &str) -> String {
+        let text: String = match self.precision {
+            Some(precision) => text.chars().take(precision).collect(),
+            None => text.to_string(),
+        };
+        self.pad("", &text, false)
+    }
+}
+
+// Parses a numeric argument: decimal, octal with a leading 0, hex with 0x,
+// or the character code of what follows a leading quote. An argument that
+// is not all digits is an error that still gives the value of its leading
+// digits, as `1` for `1.5`.
+fn parse_integer(arg: &str) -> Result<i64, (i64, String)> {
+    let trimmed = arg.trim_start();
+    if let Some(rest) = trimmed.strip_prefix(['\'', '"']) {
+        return Ok(rest.chars().next().map_or(0, |c| c as i64));
+    }
+    let (negative, unsigned) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+    let (radix, digits) = if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        (16, hex)
+    } else if unsigned.len() > 1 && unsigned.starts_with('0') {
+        (8, &unsigned[1..])
+    } else {
+        (10, unsigned)
+    };
+    let end = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+    let parsed = if end == 0 { Ok(0) } else { u64::from_str_radix(&digits[..end], radix) };
+    let value = parsed.as_ref().map_or(0, |&value| if negative { (value as i64).wrapping_neg() } else { value as i64 });
+    let complete = end == digits.len() && (end > 0 || unsigned.len() > digits.len() || trimmed.is_empty());
+    if parsed.is_ok() && complete {
+        Ok(value)
+    } else {
+        Err((value, format!("{}: invalid number", arg)))
+    }
+}
+
+fn parse_float(arg: &str) -> Result<f64, (f64, String)> {
+    let trimmed = arg.trim();
+    if !trimmed.starts_with(['\'', '"']) {
+        if let Ok(value) = trimmed.parse::<f64>() {
+            return Ok(value);
+        }
+    }
+    parse_integer(arg).map(|value| value as f64).map_err(|(value, e)| (value as f64, e))
+}
+
+// `d.ddde+XX` with at least two exponent digits, as C prints it.
+fn format_exponent(value: f64, precision: usize, upper: bool) -> String {
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    let text = format!("{}e{}{:02}", mantissa, sign, exponent.abs());
+    if upper {
+        text.to_uppercase()
+    } else {
+        text
+    }
+}
+
+fn strip_trailing_zeros(text: &str) -> String {
+    let (number, exponent) = match text.find(['e', 'E']) {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    if !number.contains('.') {
+        return text.to_string();
+    }
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", number, exponent)
+}
+
+fn format_float(spec: &Spec, conversion: char, value: f64) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    let sign = spec.sign(value.is_sign_negative() && !value.is_nan());
+    let magnitude = value.abs();
+    if !magnitude.is_finite() {
+        let text = if magnitude.is_nan() { "nan" } else { "inf" };
+        let text = if upper { text.to_uppercase() } else { text.to_string() };
+        return spec.pad(sign, &text, false);
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let body = match conversion.to_ascii_lowercase() {
+        'f' => format!("{:.*}", precision, magnitude),
+        'e' => format_exponent(magnitude, precision, upper),
+        _ => {
+            // %g picks %e or %f by the exponent and drops trailing zeros.
+            let precision = precision.max(1);
+            let exponent = if magnitude == 0.0 {
+                0
+            } else {
+                let rounded = format_exponent(magnitude, precision - 1, false);
+                rounded.split_once('e').and_then(|(_, e)| e.parse::<i32>().ok()).unwrap_or(0)
+            };
+            let text = if exponent < -4 || exponent >= precision as i32 {
+                format_exponent(magnitude, precision - 1, upper)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exponent) as usize, magnitude)
+            };
+            if spec.alternate {
+                text
+            } else {
+                strip_trailing_zeros(&text)
+            }
+        }
+    };
+    spec.pad(sign, &body, true)
+}
+
+fn format_integer(spec: &Spec, conversion: char, value: i64) -> String {
+    let (prefix, mut digits) = match conversion {
+        'd' | 'i' => (spec.sign(value < 0).to_string(), value.unsigned_abs().to_string()),
+        'u' => (String::new(), (value as u64).to_string()),
+        'o' => (String::new(), format!("{:o}", value as u64)),
+        'x' => (String::new(), format!("{:x}", value as u64)),
+        _ => (String::new(), format!("{:X}", value as u64)),
+    };
+    if let Some(precision) = spec.precision {
+        if precision == 0 && value == 0 {
+            digits.clear();
+        } else if digits.len() < precision {
+            digits = format!("{}{}", "0".repeat(precision - digits.len()), digits);
+        }
+    }
+    let mut prefix = prefix;
+    if spec.alternate && value != 0 {
+        match conversion {
+            'x' => prefix.push_str("0x"),
+            'X' => prefix.push_str("0X"),
+            'o' if !digits.starts_with('0') => digits.insert(0, '0'),
+            _ => {}
+        }
+    }
+    spec.pad(&prefix, &digits, spec.precision.is_none())
+}
+
+// The output of a printf call. `errors` holds messages for arguments that
+// could not be used; formatting carries on past them.
+pub struct Formatted {
+    pub text: String,
+    pub errors: Vec<String>,
+    // False if the format itself was invalid.
+    pub ok: bool,
+}
+
+// The largest width or precision, as for a C int.
+const MAX_FIELD: u32 = i32::MAX as u32;
+
+// Formats `args` by `format`, reusing the format while arguments remain.
+pub fn format(format: &str, args: &[&str]) -> Formatted {
+    let chars: Vec<char> = format.chars().collect();
+    let mut result = Formatted { text: String::new(), errors: Vec::new(), ok: true };
+    let mut next_arg = 0;
+
+    loop {
+        let start_arg = next_arg;
+        let mut literal = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] != '%' {
+                literal.push(chars[i]);
+                i += 1;
+                continue;
+            }
+            if chars.get(i + 1) == Some(&'%') {
+                literal.push('%');
+                i += 2;
+                continue;
+            }
+            result.text.push_str(&expand_escapes(&std::mem::take(&mut literal), true).text);
+
+            let mut take_arg = || {
+                let arg = args.get(next_arg).copied();
+                next_arg += 1;
+                arg
+            };
+
+            let mut spec = Spec::default();
+            let spec_start = i;
+            i += 1;
+            while let Some(&flag) = chars.get(i) {
+                match flag {
+                    '-' => spec.left = true,
+                    '+' => spec.plus = true,
+                    ' ' => spec.space = true,
+                    '#' => spec.alternate = true,
+                    '0' => spec.zero = true,
+                    _ => break,
+                }
+                i += 1;
+            }
+            if chars.get(i) == Some(&'*') {
+                let width = take_arg().map_or(Ok(0), parse_integer).unwrap_or(0);
+                if width.unsigned_abs() > MAX_FIELD as u64 {
+                    result.errors.push(format!("{}: invalid field width", width));
+                    result.ok = false;
+                    return result;
+                }
+                if width < 0 {
+                    spec.left = true;
+                }
+                spec.width = width.unsigned_abs() as usize;
+                i += 1;
+            } else {
+                let (width, len) = read_digits(&chars, i, usize::MAX, 10);
+                if width > MAX_FIELD {
+                    let text: String = chars[i..i + len].iter().collect();
+                    result.errors.push(format!("{}: invalid field width", text));
+                    result.ok = false;
+                    return result;
+                }
+                spec.width = width as usize;
+                i += len;
+            }
+            if chars.get(i) == Some(&'.') {
+                i += 1;
+                if chars.get(i) == Some(&'*') {
+                    let precision = take_arg().map_or(Ok(0), parse_integer).unwrap_or(0);
+                    if precision > MAX_FIELD as i64 {
+                        result.errors.push(format!("{}: invalid precision", precision));
+                        result.ok = false;
+                        return result;
+                    }
+                    spec.precision = (precision >= 0).then_some(precision as usize);
+                    i += 1;
+                } else {
+                    let (precision, len) = read_digits(&chars, i, usize::MAX, 10);
+                    if precision > MAX_FIELD {
+                        let text: String = chars[i..i + len].iter().collect();
+                        result.errors.push(format!("{}: invalid precision", text));
+                        result.ok = false;
+                        return result;
+                    }
+                    spec.precision = Some(precision as usize);
+                    i += len;
+                }
+            }
+            // Length modifiers are accepted and ignored.
+            while matches!(chars.get(i), Some('h' | 'l' | 'L' | 'j' | 'z' | 't')) {
+                i += 1;
+            }
+
+            let Some(&conversion) = chars.get(i) else {
+                let text: String = chars[spec_start..].iter().collect();
+                result.errors.push(format!("`{}': missing format character", text));
+                result.ok = false;
+                return result;
+            };
+            i += 1;
+            match conversion {
+                's' => result.text.push_str(&spec.pad_text(take_arg().unwrap_or(""))),
+                'b' => {
+                    let escaped = expand_escapes(take_arg().unwrap_or(""), false);
+                    result.text.push_str(&spec.pad_text(&escaped.text));
+                    if escaped.stop {
+                        return result;
+                    }
+                }
+                'q' => result.text.push_str(&spec.pad_text(&shell_quote(take_arg().unwrap_or("")))),
+                'c' => {
+                    let c: String = take_arg().unwrap_or("").chars().take(1).collect();
+                    result.text.push_str(&spec.pad("", &c, false));
+                }
+                'd' | 'i' | 'u' | 'o' | 'x' | 'X' => {
+                    let value = match take_arg().map_or(Ok(0), parse_integer) {
+                        Ok(value) => value,
+                        Err((value, e)) => {
+                            result.errors.push(e);
+                            value
+                        }
+                    };
+                    result.text.push_str(&format_integer(&spec, conversion, value));
+                }
+                'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                    let value = match take_arg().map_or(Ok(0.0), parse_float) {
+                        Ok(value) => value,
+                        Err((value, e)) => {
+                            result.errors.push(e);
+                            value
+                        }
+                    };
+                    result.text.push_str(&format_float(&spec, conversion, value));
+                }
+                _ => {
+                    result.errors.push(format!("`{}': invalid format character", conversion));
+                    result.ok = false;
+                    return result;
+                }
+            }
+        }
+        result.text.push_str(&expand_escapes(&literal, true).text);
+
+        // Reuse the format only while it keeps consuming arguments.
+        if next_arg >= args.len() || next_arg == start_arg {
+            break;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_integer_keeps_the_leading_digits_of_a_bad_number() {
+        assert_eq!(parse_integer("1.5"), Err((1, "1.5: invalid number".to_string())));
+        assert_eq!(parse_integer("-12abc"), Err((-12, "-12abc: invalid number".to_string())));
+        assert_eq!(parse_integer("0x1fz"), Err((31, "0x1fz: invalid number".to_string())));
+        assert_eq!(parse_integer("abc"), Err((0, "abc: invalid number".to_string())));
+        assert_eq!(parse_integer("-"), Err((0, "-: invalid number".to_string())));
+    }
+
+    #[test]
+    fn parse_integer_reads_each_base() {
+        assert_eq!(parse_integer("42"), Ok(42));
+        assert_eq!(parse_integer(" -7"), Ok(-7));
+        assert_eq!(parse_integer("010"), Ok(8));
+        assert_eq!(parse_integer("0x1F"), Ok(31));
+        assert_eq!(parse_integer("'A"), Ok(65));
+        assert_eq!(parse_integer(""), Ok(0));
+    }
+
+    #[test]
+    fn bad_integer_argument_is_reported_and_printed_in_part() {
+        let formatted = format("%d\n", &["1.5"]);
+        assert_eq!(formatted.text, "1\n");
+        assert_eq!(formatted.errors, vec!["1.5: invalid number".to_string()]);
+        let formatted = format("%.1f\n", &["3x"]);
+        assert_eq!(formatted.text, "3.0\n");
+        assert_eq!(formatted.errors.len(), 1);
+    }
+
+    #[test]
+    fn overflowing_width_is_an_error() {
+        let formatted = format("%99999999999d", &["1"]);
+        assert!(!formatted.ok);
+        assert_eq!(formatted.errors, vec!["99999999999: invalid field width".to_string()]);
+    }
+}
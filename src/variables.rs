@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+// The value of a shell variable: a plain string, an indexed array or an
+// associative array.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Scalar(String),
+    Indexed(BTreeMap<usize, String>),
+    Assoc(BTreeMap<String, String>),
+}
+
+// A shell variable. Exported variables are mirrored into the process
+// environment so that child processes see them. Arrays are never exported.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub value: Value,
+    pub exported: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: HashMap<String, Variable>,
+}
+
+impl Variables {
+    // Imports the environment the shell was started with.
+    pub fn from_env() -> Self {
+        let vars = env::vars()
+            .map(|(name, value)| (name, Variable { value: Value::Scalar(value), exported: true }))
+            .collect();
+        Variables { vars }
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&Variable> {
+        self.vars.get(name)
+    }
+
+    // The scalar value of a variable. For an array this is element 0, as
+    // `$arr` is the same as `${arr[0]}`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match &self.vars.get(name)?.value {
+            Value::Scalar(value) => Some(value),
+            Value::Indexed(map) => map.get(&0).map(|v| v.as_str()),
+            Value::Assoc(map) => map.get("0").map(|v| v.as_str()),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        let var = self.vars.entry(name.to_string()).or_insert(Variable {
+            value: Value::Scalar(String::new()),
+            exported: false,
+        });
+        match &mut var.value {
+            Value::Scalar(current) => *current = value.to_string(),
+            Value::Indexed(map) => {
+                map.insert(0, value.to_string());
+            }
+            Value::Assoc(map) => {
+                map.insert("0".to_string(), value.to_string());
+            }
+        }
+        if var.exported {
+            env::set_var(name, value);
+        }
+    }
+
+    pub fn set_value(&mut self, name: &str, value: Value) {
+        let exported = self.vars.get(name).is_some_and(|var| var.exported);
+        if let Value::Scalar(s) = &value {
+            if exported {
+                env::set_var(name, s);
+            }
+        } else if exported {
+            env::remove_var(name);
+        }
+        self.vars.insert(name.to_string(), Variable { value, exported });
+    }
+
+    pub fn is_assoc(&self, name: &str) -> bool {
+        matches!(self.vars.get(name), Some(Variable { value: Value::Assoc(_), .. }))
+    }
+
+    // Sets element `index` of an indexed array, turning a scalar into an
+    // array whose element 0 is the old value.
+    pub fn set_index(&mut self, name: &str, index: usize, value: &str) {
+        let map = self.indexed_mut(name);
+        map.insert(index, value.to_string());
+    }
+
+    pub fn set_key(&mut self, name: &str, key: &str, value: &str) {
+        if let Some(Variable { value: Value::Assoc(map), .. }) = self.vars.get_mut(name) {
+            map.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    fn indexed_mut(&mut self, name: &str) -> &mut BTreeMap<usize, String> {
+        if !matches!(self.vars.get(name), Some(Variable { value: Value::Indexed(_), .. })) {
+            let mut map = BTreeMap::new();
+            if let Some(value) = self.get(name) {
+                map.insert(0, value.to_string());
+            }
+            self.set_value(name, Value::Indexed(map));
+        }
+        match self.vars.get_mut(name) {
+            Some(Variable { value: Value::Indexed(map), .. }) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    // `declare -a name`: makes the variable an indexed array if it is not one.
+    pub fn declare_indexed(&mut self, name: &str) -> Result<(), String> {
+        if self.is_assoc(name) {
+            return Err(format!("{}: cannot convert associative to indexed array", name));
+        }
+        self.indexed_mut(name);
+        Ok(())
+    }
+
+    // `declare -A name`: makes the variable an associative array.
+    pub fn declare_assoc(&mut self, name: &str) -> Result<(), String> {
+        match self.vars.get(name).map(|var| &var.value) {
+            Some(Value::Assoc(_)) => Ok(()),
+            Some(Value::Indexed(_)) => Err(format!("{}: cannot convert indexed to associative array", name)),
+            Some(Value::Scalar(value)) => {
+                let mut map = BTreeMap::new();
+                map.insert("0".to_string(), value.clone());
+                self.set_value(name, Value::Assoc(map));
+                Ok(())
+            }
+            None => {
+                self.set_value(name, Value::Assoc(BTreeMap::new()));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn export(&mut self, name: &str) {
+        let var = self.vars.entry(name.to_string()).or_insert(Variable {
+            value: Value::Scalar(String::new()),
+            exported: false,
+        });
+        var.exported = true;
+        if let Value::Scalar(value) = &var.value {
+            env::set_var(name, value);
+        }
+    }
+
+    // Looks up one element. `key` is the already-evaluated subscript:
+    // a number for indexed arrays, any string for associative ones.
+    pub fn get_element(&self, name: &str, key: &str) -> Option<&str> {
+        match &self.vars.get(name)?.value {
+            Value::Scalar(value) => (key == "0").then_some(value.as_str()),
+            Value::Indexed(map) => map.get(&key.parse().ok()?).map(|v| v.as_str()),
+            Value::Assoc(map) => map.get(key).map(|v| v.as_str()),
+        }
+    }
+
+    // All values, in index order. A scalar is a one-element array.
+    pub fn values(&self, name: &str) -> Vec<String> {
+        match self.vars.get(name).map(|var| &var.value) {
+            Some(Value::Scalar(value)) => vec![value.clone()],
+            Some(Value::Indexed(map)) => map.values().cloned().collect(),
+            Some(Value::Assoc(map)) => map.values().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // All subscripts that are set, in order.
+    pub fn keys(&self, name: &str) -> Vec<String> {
+        match self.vars.get(name).map(|var| &var.value) {
+            Some(Value::Scalar(_)) => vec!["0".to_string()],
+            Some(Value::Indexed(map)) => map.keys().map(|k| k.to_string()).collect(),
+            Some(Value::Assoc(map)) => map.keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // The highest index of an indexed array plus one, where `arr+=(...)` appends.
+    pub fn next_index(&self, name: &str) -> usize {
+        match self.vars.get(name).map(|var| &var.value) {
+            Some(Value::Indexed(map)) => map.keys().next_back().map_or(0, |k| k + 1),
+            Some(Value::Scalar(_)) => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        if let Some(var) = self.vars.remove(name) {
+            if var.exported {
+                env::remove_var(name);
+            }
+        }
+    }
+
+    // Puts back a variable saved from `get_var`, or removes the variable if
+    // there was none.
+    pub fn restore(&mut self, name: &str, saved: Option<Variable>) {
+        self.unset(name);
+        if let Some(var) = saved {
+            if let (true, Value::Scalar(value)) = (var.exported, &var.value) {
+                env::set_var(name, value);
+            }
+            self.vars.insert(name.to_string(), var);
+        }
+    }
+
+    pub fn unset_element(&mut self, name: &str, key: &str) {
+        let Some(var) = self.vars.get_mut(name) else {
+            return;
+        };
+        match &mut var.value {
+            Value::Scalar(_) => {
+                if key == "0" {
+                    self.unset(name);
+                }
+            }
+            Value::Indexed(map) => {
+                if let Ok(index) = key.parse() {
+                    map.remove(&index);
+                }
+            }
+            Value::Assoc(map) => {
+                map.remove(key);
+            }
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.vars.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// A parsed `NAME=value`, `NAME+=value` or `NAME[subscript]=value` word.
+#[derive(Debug)]
+pub struct Assignment<'a> {
+    pub name: &'a str,
+    pub subscript: Option<&'a str>,
+    pub append: bool,
+    pub value: &'a str,
+}
+
+// Splits `name` or `name[subscript]` into its parts.
+pub fn parse_name_with_subscript(text: &str) -> Option<(&str, Option<&str>)> {
+    match text.split_once('[') {
+        Some((name, rest)) => {
+            let subscript = rest.strip_suffix(']')?;
+            (is_valid_name(name) && !subscript.is_empty()).then_some((name, Some(subscript)))
+        }
+        None => is_valid_name(text).then_some((text, None)),
+    }
+}
+
+pub fn parse_assignment(word: &str) -> Option<Assignment<'_>> {
+    // The name part cannot contain quotes, so the first `=` outside a
+    // subscript ends it.
+    let mut depth = 0;
+    let mut eq = None;
+    for (i, c) in word.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            '=' if depth == 0 => {
+                eq = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let eq = eq?;
+    let (mut target, value) = (&word[..eq], &word[eq + 1..]);
+    let append = target.ends_with('+');
+    if append {
+        target = &target[..target.len() - 1];
+    }
+    let (name, subscript) = parse_name_with_subscript(target)?;
+    Some(Assignment { name, subscript, append, value })
+}